@@ -66,6 +66,37 @@ extern "C" fn rust_main(hartid: usize, _dtb_pa: usize) -> ! {
     let t1 = time::read();
     log::info!("marchid duration = {}", t1 - t0);
 
+    // 测试控制台吞吐量
+    const LINE: &[u8; 64] = b"rustsbi-qemu console throughput benchmark 0123456789abcdefghijk\n";
+    const LINES: usize = 1000;
+    let t0 = time::read();
+
+    for _ in 0..LINES {
+        let _ = sbi_rt::console_write(Physical::new(LINE.len(), LINE.as_ptr() as _, 0));
+    }
+
+    let t1 = time::read();
+    log::info!(
+        "console_write {} bytes duration = {}",
+        LINES * LINE.len(),
+        t1 - t0
+    );
+
+    let t0 = time::read();
+
+    for _ in 0..LINES {
+        for &c in LINE {
+            let _ = sbi_rt::console_write_byte(c);
+        }
+    }
+
+    let t1 = time::read();
+    log::info!(
+        "console_write_byte {} bytes duration = {}",
+        LINES * LINE.len(),
+        t1 - t0
+    );
+
     // 打开软中断
     unsafe { sie::set_ssoft() };
     // 测试中断响应延迟
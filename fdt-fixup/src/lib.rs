@@ -0,0 +1,849 @@
+//! 修补扁平设备树。
+//!
+//! - 在 `/reserved-memory` 下为每个保留区域添加一个子节点；
+//! - 在 `/chosen` 下记录固件的身份。
+//!
+//! 修补只在设备树所在的缓冲区内移动字节，不分配内存，因此可以在宿主机上测试。
+
+#![no_std]
+#![deny(warnings, missing_docs)]
+
+use core::{
+    fmt::{self, Display, Formatter, Write},
+    ptr,
+};
+
+/// 设备树魔数。
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// 头中各字段的偏移。
+mod header {
+    pub const TOTAL_SIZE: usize = 4;
+    pub const OFF_DT_STRUCT: usize = 8;
+    pub const OFF_DT_STRINGS: usize = 12;
+    pub const OFF_MEM_RSVMAP: usize = 16;
+    pub const SIZE_DT_STRINGS: usize = 32;
+    pub const SIZE_DT_STRUCT: usize = 36;
+    /// 头的长度。
+    pub const LEN: usize = 40;
+}
+
+/// 固件实现，也用于识别已经修补过的设备树。
+pub const PROP_IMPLEMENTATION: &str = "rustsbi,implementation";
+/// 固件版本。
+pub const PROP_VERSION: &str = "rustsbi,version";
+
+/// `/reserved-memory` 下的一个保留区域。
+pub struct Reserved<'a> {
+    /// 节点名，不含单元地址。
+    pub name: &'a str,
+    /// 起始物理地址。
+    pub base: u64,
+    /// 长度。
+    pub size: u64,
+    /// 特权软件不能映射这个区域。
+    pub no_map: bool,
+}
+
+/// 记录在 `/chosen` 下的固件身份。
+pub struct Identity<'a> {
+    /// [`PROP_IMPLEMENTATION`] 的值。
+    pub implementation: &'a str,
+    /// [`PROP_VERSION`] 的值。
+    pub version: &'a str,
+}
+
+/// 修补设备树失败。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FixupError {
+    /// 魔数不对。
+    InvalidMagic(u32),
+    /// 结构块格式错误，或各块的排列不受支持。
+    Malformed,
+    /// 保留区域或固件身份太多太长，插入的片段放不下。
+    TooLarge,
+    /// 修补后的设备树放不下。
+    NoSpace {
+        /// 需要的字节数。
+        need: usize,
+        /// 缓冲区的字节数。
+        capacity: usize,
+    },
+}
+
+impl Display for FixupError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidMagic(magic) => write!(f, "invalid magic {magic:#x}"),
+            Self::Malformed => write!(f, "malformed structure block"),
+            Self::TooLarge => write!(f, "reserved regions or identity too large"),
+            Self::NoSpace { need, capacity } => {
+                write!(f, "need {need:#x} bytes but only {capacity:#x} available")
+            }
+        }
+    }
+}
+
+/// 修补从 `blob` 开头开始的设备树，`blob` 的其余部分是修补可用的空间。
+///
+/// 返回修补后设备树的长度。已经修补过的设备树不再修改。
+pub fn fixup(
+    blob: &mut [u8],
+    reserved: &[Reserved],
+    identity: &Identity,
+) -> Result<usize, FixupError> {
+    if blob.len() < header::LEN {
+        return Err(FixupError::Malformed);
+    }
+    let mut fdt = Fdt(blob);
+    let magic = fdt.u32(0);
+    if magic != FDT_MAGIC {
+        return Err(FixupError::InvalidMagic(magic));
+    }
+    let scan = fdt.scan()?;
+    if scan.patched {
+        return Ok(fdt.header(header::TOTAL_SIZE));
+    }
+    // 子节点按 `/reserved-memory` 的单元数编码，新建的 `/reserved-memory` 与根节点相同
+    let (address_cells, size_cells) = scan.reserved_memory_cells.unwrap_or(scan.root_cells);
+    if address_cells > 2 || size_cells > 2 {
+        return Err(FixupError::Malformed);
+    }
+
+    let mut strings = Strings::new(&fdt);
+    // 新的 `/reserved-memory` 子节点
+    let mut nodes = Struct::new();
+    if scan.reserved_memory_end.is_none() {
+        nodes.begin_node(format_args!("reserved-memory"));
+        nodes.prop_u32(strings.offset("#address-cells"), address_cells);
+        nodes.prop_u32(strings.offset("#size-cells"), size_cells);
+        nodes.prop(strings.offset("ranges"), &[]);
+    }
+    for region in reserved {
+        nodes.begin_node(format_args!("{}@{:x}", region.name, region.base));
+        nodes.prop_reg(
+            strings.offset("reg"),
+            (address_cells, region.base),
+            (size_cells, region.size),
+        );
+        if region.no_map {
+            nodes.prop(strings.offset("no-map"), &[]);
+        }
+        nodes.end_node();
+    }
+    if scan.reserved_memory_end.is_none() {
+        nodes.end_node();
+    }
+    // `/chosen` 下的固件身份
+    let mut chosen = Struct::new();
+    if scan.chosen_begin.is_none() {
+        chosen.begin_node(format_args!("chosen"));
+    }
+    chosen.prop_str(strings.offset(PROP_IMPLEMENTATION), identity.implementation);
+    chosen.prop_str(strings.offset(PROP_VERSION), identity.version);
+    if scan.chosen_begin.is_none() {
+        chosen.end_node();
+    }
+    if nodes.overflow || chosen.overflow || strings.overflow {
+        return Err(FixupError::TooLarge);
+    }
+
+    // 插入点按偏移排序
+    let mut inserts = [
+        (
+            scan.reserved_memory_end.unwrap_or(scan.root_end),
+            nodes.bytes(),
+        ),
+        (scan.chosen_begin.unwrap_or(scan.root_end), chosen.bytes()),
+    ];
+    inserts.sort_unstable_by_key(|(offset, _)| *offset);
+    let (appended, len) = (strings.appended, strings.len);
+    fdt.splice(&scan, &inserts, &appended[..len])
+}
+
+/// 结构块中找到的位置。
+struct Scan {
+    /// 根节点的 `#address-cells` 和 `#size-cells`。
+    root_cells: (u32, u32),
+    /// 已有的 `/reserved-memory` 节点的 `#address-cells` 和 `#size-cells`。
+    reserved_memory_cells: Option<(u32, u32)>,
+    /// 根节点 `FDT_END_NODE` 的偏移。
+    root_end: usize,
+    /// `/reserved-memory` 节点 `FDT_END_NODE` 的偏移。
+    reserved_memory_end: Option<usize>,
+    /// `/chosen` 节点第一个属性的偏移。
+    chosen_begin: Option<usize>,
+    /// 设备树已经修补过。
+    patched: bool,
+    /// 结构块结束的偏移。
+    struct_end: usize,
+}
+
+struct Fdt<'a>(&'a mut [u8]);
+
+impl Fdt<'_> {
+    #[inline]
+    fn u32(&self, offset: usize) -> u32 {
+        u32::from_be_bytes(self.0[offset..][..4].try_into().unwrap())
+    }
+
+    #[inline]
+    fn set_u32(&mut self, offset: usize, val: u32) {
+        self.0[offset..][..4].copy_from_slice(&val.to_be_bytes());
+    }
+
+    #[inline]
+    fn header(&self, field: usize) -> usize {
+        self.u32(field) as usize
+    }
+
+    /// 读 `offset` 处以 0 结尾的字符串。
+    fn cstr(&self, offset: usize) -> Option<&[u8]> {
+        let bytes = self.0.get(offset..)?;
+        bytes.iter().position(|b| *b == 0).map(|len| &bytes[..len])
+    }
+
+    fn strings(&self) -> &[u8] {
+        let offset = self.header(header::OFF_DT_STRINGS);
+        &self.0[offset..][..self.header(header::SIZE_DT_STRINGS)]
+    }
+
+    /// 扫描结构块，只关心根节点和它的直接子节点。
+    fn scan(&self) -> Result<Scan, FixupError> {
+        #[derive(PartialEq, Eq)]
+        enum Node {
+            ReservedMemory,
+            Chosen,
+            Other,
+        }
+
+        let total_size = self.header(header::TOTAL_SIZE);
+        if total_size > self.0.len()
+            || self.header(header::OFF_DT_STRINGS) + self.header(header::SIZE_DT_STRINGS)
+                > total_size
+        {
+            return Err(FixupError::Malformed);
+        }
+        let mut ans = Scan {
+            root_cells: (2, 1),
+            reserved_memory_cells: None,
+            root_end: 0,
+            reserved_memory_end: None,
+            chosen_begin: None,
+            patched: false,
+            struct_end: 0,
+        };
+        let mut offset = self.header(header::OFF_DT_STRUCT);
+        let mut depth = 0usize;
+        let mut node = Node::Other;
+        loop {
+            if offset + 4 > total_size {
+                return Err(FixupError::Malformed);
+            }
+            match self.u32(offset) {
+                FDT_BEGIN_NODE => {
+                    let name = self.cstr(offset + 4).ok_or(FixupError::Malformed)?;
+                    depth += 1;
+                    if depth == 2 {
+                        node = match name {
+                            b"reserved-memory" => Node::ReservedMemory,
+                            b"chosen" => Node::Chosen,
+                            _ => Node::Other,
+                        };
+                        if node == Node::ReservedMemory {
+                            // 节点没有描述时采用规范的默认值
+                            ans.reserved_memory_cells = Some((2, 1));
+                        }
+                    }
+                    offset = align4(offset + 4 + name.len() + 1);
+                    if depth == 2 && node == Node::Chosen {
+                        ans.chosen_begin = Some(offset);
+                    }
+                }
+                FDT_END_NODE => {
+                    match depth {
+                        0 => return Err(FixupError::Malformed),
+                        1 => ans.root_end = offset,
+                        2 if node == Node::ReservedMemory => ans.reserved_memory_end = Some(offset),
+                        _ => {}
+                    }
+                    depth -= 1;
+                    offset += 4;
+                }
+                FDT_PROP => {
+                    if offset + 12 > total_size {
+                        return Err(FixupError::Malformed);
+                    }
+                    let len = self.u32(offset + 4) as usize;
+                    let name = self.u32(offset + 8) as usize;
+                    let name = self
+                        .strings()
+                        .get(name..)
+                        .and_then(|s| s.iter().position(|b| *b == 0).map(|len| &s[..len]))
+                        .ok_or(FixupError::Malformed)?;
+                    let value = offset + 12;
+                    if value + len > total_size {
+                        return Err(FixupError::Malformed);
+                    }
+                    let cells = (len == 4).then(|| self.u32(value));
+                    match (depth, name) {
+                        (1, b"#address-cells") => ans.root_cells.0 = cells.unwrap_or(2),
+                        (1, b"#size-cells") => ans.root_cells.1 = cells.unwrap_or(1),
+                        (2, b"#address-cells") if node == Node::ReservedMemory => {
+                            if let (Some(ours), Some(cells)) =
+                                (&mut ans.reserved_memory_cells, cells)
+                            {
+                                ours.0 = cells;
+                            }
+                        }
+                        (2, b"#size-cells") if node == Node::ReservedMemory => {
+                            if let (Some(ours), Some(cells)) =
+                                (&mut ans.reserved_memory_cells, cells)
+                            {
+                                ours.1 = cells;
+                            }
+                        }
+                        (2, name) if node == Node::Chosen => {
+                            ans.patched |= name == PROP_IMPLEMENTATION.as_bytes()
+                        }
+                        _ => {}
+                    }
+                    offset = align4(value + len);
+                }
+                FDT_NOP => offset += 4,
+                FDT_END if depth == 0 => {
+                    ans.struct_end = offset + 4;
+                    break;
+                }
+                _ => return Err(FixupError::Malformed),
+            }
+        }
+        // 只支持保留内存块、结构块、字符串块依次排列的设备树
+        if ans.root_end == 0
+            || self.header(header::OFF_MEM_RSVMAP) > self.header(header::OFF_DT_STRUCT)
+            || self.header(header::OFF_DT_STRINGS) < ans.struct_end
+        {
+            return Err(FixupError::Malformed);
+        }
+        Ok(ans)
+    }
+
+    /// 在结构块中插入 `inserts`，在字符串块后追加 `strings`，返回新的总长度。
+    ///
+    /// 先把字符串块移到新位置，再从后往前移动结构块的各段。
+    fn splice(
+        &mut self,
+        scan: &Scan,
+        inserts: &[(usize, &[u8])],
+        strings: &[u8],
+    ) -> Result<usize, FixupError> {
+        let off_struct = self.header(header::OFF_DT_STRUCT);
+        let off_strings = self.header(header::OFF_DT_STRINGS);
+        let size_strings = self.header(header::SIZE_DT_STRINGS);
+        let grow: usize = inserts.iter().map(|(_, bytes)| bytes.len()).sum();
+        let struct_end = scan.struct_end + grow;
+        let new_strings = struct_end.max(off_strings);
+        let end = new_strings + size_strings + strings.len();
+        if end > self.0.len() {
+            return Err(FixupError::NoSpace {
+                need: end,
+                capacity: self.0.len(),
+            });
+        }
+        let base = self.0.as_mut_ptr();
+        unsafe {
+            ptr::copy(base.add(off_strings), base.add(new_strings), size_strings);
+            ptr::copy_nonoverlapping(
+                strings.as_ptr(),
+                base.add(new_strings + size_strings),
+                strings.len(),
+            );
+            let mut tail = scan.struct_end;
+            let mut shift = grow;
+            for (offset, bytes) in inserts.iter().rev() {
+                ptr::copy(base.add(*offset), base.add(*offset + shift), tail - offset);
+                shift -= bytes.len();
+                ptr::copy_nonoverlapping(bytes.as_ptr(), base.add(offset + shift), bytes.len());
+                tail = *offset;
+            }
+        }
+        let total_size = end.max(self.header(header::TOTAL_SIZE));
+        self.set_u32(header::TOTAL_SIZE, total_size as _);
+        self.set_u32(header::OFF_DT_STRINGS, new_strings as _);
+        self.set_u32(header::SIZE_DT_STRINGS, (size_strings + strings.len()) as _);
+        self.set_u32(header::SIZE_DT_STRUCT, (struct_end - off_struct) as _);
+        Ok(total_size)
+    }
+}
+
+#[inline]
+const fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+/// 字符串块。已有的字符串直接复用，没有的追加在后面。
+struct Strings<'a> {
+    existing: &'a [u8],
+    appended: [u8; 128],
+    len: usize,
+    overflow: bool,
+}
+
+impl<'a> Strings<'a> {
+    fn new(fdt: &'a Fdt) -> Self {
+        Self {
+            existing: fdt.strings(),
+            appended: [0; 128],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// 找到或追加 `name`，返回它在字符串块中的偏移。
+    fn offset(&mut self, name: &str) -> u32 {
+        let name = name.as_bytes();
+        let find = |block: &[u8]| {
+            block
+                .split(|b| *b == 0)
+                .scan(0, |offset, s| {
+                    let current = *offset;
+                    *offset += s.len() + 1;
+                    Some((current, s))
+                })
+                .find(|(_, s)| *s == name)
+                .map(|(offset, _)| offset)
+        };
+        if let Some(offset) = find(self.existing) {
+            return offset as _;
+        }
+        if let Some(offset) = find(&self.appended[..self.len]) {
+            return (self.existing.len() + offset) as _;
+        }
+        let offset = self.existing.len() + self.len;
+        match self.appended.get_mut(self.len..self.len + name.len() + 1) {
+            Some(buf) => {
+                buf[..name.len()].copy_from_slice(name);
+                self.len += name.len() + 1;
+            }
+            None => self.overflow = true,
+        }
+        offset as _
+    }
+}
+
+/// 待插入的结构块片段。
+struct Struct {
+    buf: [u8; 512],
+    len: usize,
+    overflow: bool,
+}
+
+impl Struct {
+    const fn new() -> Self {
+        Self {
+            buf: [0; 512],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.write_bytes(bytes);
+        self.len = align4(self.len).min(self.buf.len());
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        match self.buf.get_mut(self.len..self.len + bytes.len()) {
+            Some(buf) => {
+                buf.copy_from_slice(bytes);
+                self.len += bytes.len();
+            }
+            None => self.overflow = true,
+        }
+    }
+
+    fn begin_node(&mut self, name: fmt::Arguments) {
+        self.push(&FDT_BEGIN_NODE.to_be_bytes());
+        write!(self, "{name}\0").unwrap();
+        self.len = align4(self.len).min(self.buf.len());
+    }
+
+    fn end_node(&mut self) {
+        self.push(&FDT_END_NODE.to_be_bytes());
+    }
+
+    fn prop(&mut self, name: u32, value: &[u8]) {
+        self.push(&FDT_PROP.to_be_bytes());
+        self.push(&(value.len() as u32).to_be_bytes());
+        self.push(&name.to_be_bytes());
+        self.push(value);
+    }
+
+    fn prop_u32(&mut self, name: u32, value: u32) {
+        self.prop(name, &value.to_be_bytes());
+    }
+
+    fn prop_str(&mut self, name: u32, value: &str) {
+        self.push(&FDT_PROP.to_be_bytes());
+        self.push(&(value.len() as u32 + 1).to_be_bytes());
+        self.push(&name.to_be_bytes());
+        write!(self, "{value}\0").unwrap();
+        self.len = align4(self.len).min(self.buf.len());
+    }
+
+    /// 按 `(cells, value)` 编码地址和长度。
+    fn prop_reg(&mut self, name: u32, addr: (u32, u64), size: (u32, u64)) {
+        let mut value = [0u8; 16];
+        let mut len = 0;
+        for (cells, val) in [addr, size] {
+            for i in (0..cells).rev() {
+                let cell = val.checked_shr(i * 32).unwrap_or(0) as u32;
+                value[len..][..4].copy_from_slice(&cell.to_be_bytes());
+                len += 4;
+            }
+        }
+        self.prop(name, &value[..len]);
+    }
+}
+
+impl Write for Struct {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use dtb_walker::{Dtb, DtbObj, HeaderError as E, Property, WalkOperation::*};
+    use std::{ops::Range, string::String, vec::Vec};
+
+    const CAPACITY: usize = 4096;
+
+    const IDENTITY: Identity = Identity {
+        implementation: "RustSBI-QEMU",
+        version: "0.2.0",
+    };
+
+    const REGIONS: [Reserved; 2] = [
+        Reserved {
+            name: "rustsbi",
+            base: 0x8000_0000,
+            size: 0x1e_0000,
+            no_map: true,
+        },
+        Reserved {
+            name: "rustsbi-dtb",
+            base: 0x801e_0000,
+            size: 0x2_0000,
+            no_map: false,
+        },
+    ];
+
+    /// 设备树缓冲区，`dtb-walker` 要求首部对齐。
+    #[repr(C, align(8))]
+    struct Blob([u8; CAPACITY]);
+
+    /// 按顺序构造结构块和字符串块。
+    #[derive(Default)]
+    struct Builder {
+        structure: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl Builder {
+        fn begin(mut self, name: &str) -> Self {
+            self.structure.extend(FDT_BEGIN_NODE.to_be_bytes());
+            self.structure.extend(name.as_bytes());
+            self.structure.push(0);
+            self.pad();
+            self
+        }
+
+        fn end(mut self) -> Self {
+            self.structure.extend(FDT_END_NODE.to_be_bytes());
+            self
+        }
+
+        fn prop(mut self, name: &str, value: &[u8]) -> Self {
+            let offset = self.strings.len() as u32;
+            self.strings.extend(name.as_bytes());
+            self.strings.push(0);
+            self.structure.extend(FDT_PROP.to_be_bytes());
+            self.structure.extend((value.len() as u32).to_be_bytes());
+            self.structure.extend(offset.to_be_bytes());
+            self.structure.extend(value);
+            self.pad();
+            self
+        }
+
+        fn prop_u32(self, name: &str, value: u32) -> Self {
+            self.prop(name, &value.to_be_bytes())
+        }
+
+        fn pad(&mut self) {
+            while self.structure.len() % 4 != 0 {
+                self.structure.push(0);
+            }
+        }
+
+        /// 生成设备树，返回缓冲区和设备树长度。
+        fn build(mut self) -> (std::boxed::Box<Blob>, usize) {
+            self.structure.extend(FDT_END.to_be_bytes());
+            let off_rsvmap = header::LEN;
+            let off_struct = off_rsvmap + 16;
+            let off_strings = off_struct + self.structure.len();
+            let total = off_strings + self.strings.len();
+            let mut blob = std::boxed::Box::new(Blob([0; CAPACITY]));
+            let fields = [
+                (0, FDT_MAGIC),
+                (header::TOTAL_SIZE, total as u32),
+                (header::OFF_DT_STRUCT, off_struct as u32),
+                (header::OFF_DT_STRINGS, off_strings as u32),
+                (header::OFF_MEM_RSVMAP, off_rsvmap as u32),
+                // version 和 last_comp_version
+                (20, 17),
+                (24, 16),
+                (header::SIZE_DT_STRINGS, self.strings.len() as u32),
+                (header::SIZE_DT_STRUCT, self.structure.len() as u32),
+            ];
+            for (offset, value) in fields {
+                blob.0[offset..][..4].copy_from_slice(&value.to_be_bytes());
+            }
+            blob.0[off_struct..][..self.structure.len()].copy_from_slice(&self.structure);
+            blob.0[off_strings..][..self.strings.len()].copy_from_slice(&self.strings);
+            (blob, total)
+        }
+    }
+
+    /// 没有 `/reserved-memory` 和 `/chosen` 的设备树，地址和长度都是两个单元。
+    fn plain() -> Builder {
+        Builder::default()
+            .begin("")
+            .prop_u32("#address-cells", 2)
+            .prop_u32("#size-cells", 2)
+            .begin("memory@80000000")
+            .prop("device_type", b"memory\0")
+            .prop(
+                "reg",
+                &[0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0x08, 0, 0, 0],
+            )
+            .end()
+    }
+
+    /// 设备树中的节点、属性和 `reg`。
+    #[derive(Default)]
+    struct Tree {
+        nodes: Vec<String>,
+        props: Vec<(String, String, Vec<u8>)>,
+        regs: Vec<(String, Vec<Range<usize>>)>,
+    }
+
+    impl Tree {
+        fn parse(blob: &[u8]) -> Self {
+            let dtb = Dtb::from_slice_filtered(blob, |e| matches!(e, E::LastCompVersion(_)))
+                .unwrap_or_else(|_| panic!("invalid device tree"));
+            assert_eq!(dtb.total_size(), blob.len());
+            let mut tree = Self::default();
+            let path = |ctx: &dtb_walker::Context| {
+                let mut buf = [0u8; 128];
+                let len = ctx.fmt_path(&mut buf).unwrap();
+                String::from_utf8(buf[..len].to_vec()).unwrap()
+            };
+            dtb.walk(|ctx, obj| {
+                match obj {
+                    DtbObj::SubNode { name } => {
+                        let name = name.as_str().unwrap();
+                        let parent = path(ctx);
+                        tree.nodes.push(std::format!("{}/{name}", parent));
+                        return StepInto;
+                    }
+                    DtbObj::Property(Property::Reg(reg)) => {
+                        tree.regs.push((path(ctx), reg.collect()));
+                    }
+                    DtbObj::Property(Property::General { name, value }) => {
+                        let name = String::from(name.as_str().unwrap());
+                        tree.props.push((path(ctx), name, value.to_vec()));
+                    }
+                    DtbObj::Property(_) => {}
+                }
+                StepOver
+            });
+            tree
+        }
+
+        fn has_node(&self, path: &str) -> bool {
+            self.nodes.iter().any(|node| node == path)
+        }
+
+        fn prop(&self, path: &str, name: &str) -> Option<&[u8]> {
+            self.props
+                .iter()
+                .find(|(p, n, _)| p == path && n == name)
+                .map(|(_, _, value)| value.as_slice())
+        }
+
+        /// 节点的 `reg` 中唯一的区间。
+        fn reg(&self, path: &str) -> Option<Range<usize>> {
+            let (_, reg) = self.regs.iter().find(|(p, _)| p == path)?;
+            assert_eq!(reg.len(), 1, "{path} has {} ranges", reg.len());
+            Some(reg[0].clone())
+        }
+    }
+
+    #[test]
+    fn add_reserved_memory_and_chosen() {
+        let (mut blob, _) = plain().end().build();
+        let len = fixup(&mut blob.0, &REGIONS, &IDENTITY).unwrap();
+        let tree = Tree::parse(&blob.0[..len]);
+        // `dtb-walker` 不报告单元数，按单元数解析出正确的 `reg` 说明单元数正确
+        assert_eq!(tree.prop("/reserved-memory", "ranges"), Some(&[][..]));
+        assert_eq!(
+            tree.reg("/reserved-memory/rustsbi@80000000"),
+            Some(0x8000_0000..0x801e_0000)
+        );
+        assert_eq!(
+            tree.prop("/reserved-memory/rustsbi@80000000", "no-map"),
+            Some(&[][..])
+        );
+        assert_eq!(
+            tree.reg("/reserved-memory/rustsbi-dtb@801e0000"),
+            Some(0x801e_0000..0x8020_0000)
+        );
+        assert_eq!(
+            tree.prop("/reserved-memory/rustsbi-dtb@801e0000", "no-map"),
+            None
+        );
+        assert_eq!(
+            tree.prop("/chosen", PROP_IMPLEMENTATION),
+            Some(&b"RustSBI-QEMU\0"[..])
+        );
+        assert_eq!(tree.prop("/chosen", PROP_VERSION), Some(&b"0.2.0\0"[..]));
+        // 原有的节点不变
+        assert_eq!(tree.reg("/memory@80000000"), Some(0x8000_0000..0x8800_0000));
+        assert_eq!(
+            tree.prop("/memory@80000000", "device_type"),
+            Some(&b"memory\0"[..])
+        );
+    }
+
+    #[test]
+    fn honour_reserved_memory_cells() {
+        let (mut blob, _) = plain()
+            .begin("reserved-memory")
+            .prop_u32("#address-cells", 1)
+            .prop_u32("#size-cells", 1)
+            .prop("ranges", &[])
+            .begin("mmode_resv0@80000000")
+            .prop("reg", &[0x80, 0, 0, 0, 0, 0x04, 0, 0])
+            .end()
+            .end()
+            .end()
+            .build();
+        let len = fixup(&mut blob.0, &REGIONS, &IDENTITY).unwrap();
+        let tree = Tree::parse(&blob.0[..len]);
+        // `dtb-walker` 按父节点的单元数解析 `reg`，单元数不对时区间也不对
+        assert_eq!(
+            tree.reg("/reserved-memory/rustsbi@80000000"),
+            Some(0x8000_0000..0x801e_0000)
+        );
+        assert_eq!(
+            tree.reg("/reserved-memory/mmode_resv0@80000000"),
+            Some(0x8000_0000..0x8004_0000)
+        );
+        assert!(!tree.has_node("/reserved-memory/reserved-memory"));
+        assert_eq!(
+            tree.nodes
+                .iter()
+                .filter(|node| *node == "/reserved-memory")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn keep_existing_chosen() {
+        let (mut blob, _) = Builder::default()
+            .begin("")
+            .prop_u32("#address-cells", 2)
+            .prop_u32("#size-cells", 2)
+            .begin("chosen")
+            .prop("bootargs", b"console=ttyS0\0")
+            .end()
+            .begin("memory@80000000")
+            .prop(
+                "reg",
+                &[0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0x08, 0, 0, 0],
+            )
+            .end()
+            .end()
+            .build();
+        let len = fixup(&mut blob.0, &REGIONS, &IDENTITY).unwrap();
+        let tree = Tree::parse(&blob.0[..len]);
+        assert_eq!(
+            tree.prop("/chosen", "bootargs"),
+            Some(&b"console=ttyS0\0"[..])
+        );
+        assert_eq!(
+            tree.prop("/chosen", PROP_IMPLEMENTATION),
+            Some(&b"RustSBI-QEMU\0"[..])
+        );
+        assert_eq!(
+            tree.nodes.iter().filter(|node| *node == "/chosen").count(),
+            1
+        );
+        assert_eq!(tree.reg("/memory@80000000"), Some(0x8000_0000..0x8800_0000));
+        assert!(tree.has_node("/reserved-memory/rustsbi@80000000"));
+    }
+
+    #[test]
+    fn patch_only_once() {
+        let (mut blob, _) = plain().end().build();
+        let len = fixup(&mut blob.0, &REGIONS, &IDENTITY).unwrap();
+        let patched = blob.0;
+        assert_eq!(fixup(&mut blob.0, &REGIONS, &IDENTITY), Ok(len));
+        assert_eq!(blob.0, patched);
+    }
+
+    #[test]
+    fn report_no_space() {
+        let (mut blob, len) = plain().end().build();
+        let original = blob.0;
+        let ans = fixup(&mut blob.0[..len + 16], &REGIONS, &IDENTITY);
+        assert!(matches!(ans, Err(FixupError::NoSpace { capacity, .. }) if capacity == len + 16));
+        // 空间不够时不修改设备树
+        assert_eq!(blob.0, original);
+    }
+
+    #[test]
+    fn reject_invalid_magic() {
+        let (mut blob, _) = plain().end().build();
+        blob.0[..4].copy_from_slice(&0x1234_5678u32.to_be_bytes());
+        assert_eq!(
+            fixup(&mut blob.0, &REGIONS, &IDENTITY),
+            Err(FixupError::InvalidMagic(0x1234_5678))
+        );
+    }
+
+    #[test]
+    fn reject_truncated_structure() {
+        let (mut blob, _) = plain().build();
+        assert_eq!(
+            fixup(&mut blob.0, &REGIONS, &IDENTITY),
+            Err(FixupError::Malformed)
+        );
+    }
+}
@@ -51,6 +51,27 @@ impl<T> HsmCell<T> {
     pub fn remote(&self) -> RemoteHsmCell<'_, T> {
         RemoteHsmCell(self)
     }
+
+    /// 从 `from` 状态转移到 `to` 状态，失败时返回当前状态。
+    ///
+    /// 成功的转移以 `AcqRel` 序发生，转移前的写入对观察到新状态的硬件线程可见。
+    #[inline]
+    fn transit(&self, from: usize, to: usize) -> Result<(), usize> {
+        self.status
+            .compare_exchange(from, to, Ordering::AcqRel, Ordering::Acquire)
+            .map(|_| ())
+            .map_err(|s| match s {
+                HART_STATE_START_PENDING_EXT => hart_state::START_PENDING,
+                normal => normal,
+            })
+    }
+}
+
+impl<T> Default for HsmCell<T> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<T> LocalHsmCell<'_, T> {
@@ -71,24 +92,48 @@ impl<T> LocalHsmCell<'_, T> {
         }
     }
 
-    /// 关闭。
+    /// 开始关闭，将状态从启动设置为关闭挂起，失败时返回当前状态。
     #[inline]
-    pub fn stop(&self) {
-        self.0.status.store(hart_state::STOPPED, Ordering::Release)
+    pub fn stop(&self) -> Result<(), usize> {
+        self.0
+            .transit(hart_state::STARTED, hart_state::STOP_PENDING)
     }
 
-    /// 关闭。
+    /// 完成关闭，将状态从关闭挂起设置为关闭，失败时返回当前状态。
+    ///
+    /// 此后其他硬件线程可以重新启动这个硬件线程。
     #[inline]
-    pub fn suspend(&self) {
+    pub fn stopped(&self) -> Result<(), usize> {
         self.0
-            .status
-            .store(hart_state::SUSPENDED, Ordering::Relaxed)
+            .transit(hart_state::STOP_PENDING, hart_state::STOPPED)
+    }
+
+    /// 开始挂起，将状态从启动设置为挂起挂起，失败时返回当前状态。
+    #[inline]
+    pub fn suspend(&self) -> Result<(), usize> {
+        self.0
+            .transit(hart_state::STARTED, hart_state::SUSPEND_PENDING)
+    }
+
+    /// 完成挂起，将状态从挂起挂起设置为挂起，失败时返回当前状态。
+    #[inline]
+    pub fn suspended(&self) -> Result<(), usize> {
+        self.0
+            .transit(hart_state::SUSPEND_PENDING, hart_state::SUSPENDED)
+    }
+
+    /// 开始恢复，将状态从挂起设置为恢复挂起，失败时返回当前状态。
+    #[inline]
+    pub fn resume(&self) -> Result<(), usize> {
+        self.0
+            .transit(hart_state::SUSPENDED, hart_state::RESUME_PENDING)
     }
 
-    /// 关闭。
+    /// 完成恢复，将状态从恢复挂起设置为启动，失败时返回当前状态。
     #[inline]
-    pub fn resume(&self) {
-        self.0.status.store(hart_state::STARTED, Ordering::Relaxed)
+    pub fn resumed(&self) -> Result<(), usize> {
+        self.0
+            .transit(hart_state::RESUME_PENDING, hart_state::STARTED)
     }
 }
 
@@ -118,20 +163,210 @@ impl<T> RemoteHsmCell<'_, T> {
     }
 
     /// 取出当前状态。
+    ///
+    /// 可能是 SBI 规范定义的任何状态，包括各种挂起状态。
     #[inline]
     pub fn sbi_get_status(&self) -> usize {
-        match self.0.status.load(Ordering::Relaxed) {
+        match self.0.status.load(Ordering::Acquire) {
             HART_STATE_START_PENDING_EXT => hart_state::START_PENDING,
             normal => normal,
         }
     }
 
     /// 判断这个 HART 能否接收 IPI。
+    ///
+    /// 挂起和正在挂起或恢复的硬件线程也能接收，核间中断会唤醒挂起的硬件线程。
     #[inline]
     pub fn allow_ipi(&self) -> bool {
         matches!(
             self.0.status.load(Ordering::Relaxed),
-            hart_state::STARTED | hart_state::SUSPENDED
+            hart_state::STARTED
+                | hart_state::SUSPEND_PENDING
+                | hart_state::SUSPENDED
+                | hart_state::RESUME_PENDING
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SBI 规范定义的所有状态。
+    const STATES: [usize; 7] = [
+        hart_state::STARTED,
+        hart_state::STOPPED,
+        hart_state::START_PENDING,
+        hart_state::STOP_PENDING,
+        hart_state::SUSPENDED,
+        hart_state::SUSPEND_PENDING,
+        hart_state::RESUME_PENDING,
+    ];
+
+    /// 当前硬件线程发起的状态转移。
+    type Transit = fn(&LocalHsmCell<'_, u32>) -> Result<(), usize>;
+
+    /// 当前硬件线程发起的每种转移，和它合法的起止状态。
+    fn transits() -> [(&'static str, Transit, usize, usize); 6] {
+        [
+            (
+                "stop",
+                |c| c.stop(),
+                hart_state::STARTED,
+                hart_state::STOP_PENDING,
+            ),
+            (
+                "stopped",
+                |c| c.stopped(),
+                hart_state::STOP_PENDING,
+                hart_state::STOPPED,
+            ),
+            (
+                "suspend",
+                |c| c.suspend(),
+                hart_state::STARTED,
+                hart_state::SUSPEND_PENDING,
+            ),
+            (
+                "suspended",
+                |c| c.suspended(),
+                hart_state::SUSPEND_PENDING,
+                hart_state::SUSPENDED,
+            ),
+            (
+                "resume",
+                |c| c.resume(),
+                hart_state::SUSPENDED,
+                hart_state::RESUME_PENDING,
+            ),
+            (
+                "resumed",
+                |c| c.resumed(),
+                hart_state::RESUME_PENDING,
+                hart_state::STARTED,
+            ),
+        ]
+    }
+
+    /// 处于 `status` 状态的共享对象。
+    fn cell(status: usize) -> HsmCell<u32> {
+        let cell = HsmCell::new();
+        cell.status.store(status, Ordering::Relaxed);
+        cell
+    }
+
+    #[test]
+    fn start_stopped_hart() {
+        let cell = HsmCell::new();
+        assert_eq!(cell.remote().sbi_get_status(), hart_state::STOPPED);
+        assert!(cell.remote().start(7));
+        assert_eq!(cell.remote().sbi_get_status(), hart_state::START_PENDING);
+        assert_eq!(unsafe { cell.local() }.start(), Ok(7));
+        assert_eq!(cell.remote().sbi_get_status(), hart_state::STARTED);
+    }
+
+    #[test]
+    fn start_only_stopped_hart() {
+        for status in STATES.into_iter().filter(|s| *s != hart_state::STOPPED) {
+            let cell = cell(status);
+            assert!(!cell.remote().start(7), "start from {status}");
+            assert_eq!(cell.remote().sbi_get_status(), status);
+            assert_eq!(unsafe { &*cell.val.get() }, &None);
+        }
+    }
+
+    #[test]
+    fn take_only_pending_start() {
+        for status in STATES
+            .into_iter()
+            .filter(|s| *s != hart_state::START_PENDING)
+        {
+            let cell = cell(status);
+            assert_eq!(unsafe { cell.local() }.start(), Err(status));
+            assert_eq!(cell.remote().sbi_get_status(), status);
+        }
+    }
+
+    #[test]
+    fn stop_and_restart() {
+        let cell = cell(hart_state::STARTED);
+        let local = unsafe { cell.local() };
+        assert_eq!(local.stop(), Ok(()));
+        assert_eq!(cell.remote().sbi_get_status(), hart_state::STOP_PENDING);
+        assert_eq!(local.stopped(), Ok(()));
+        assert_eq!(cell.remote().sbi_get_status(), hart_state::STOPPED);
+        assert!(cell.remote().start(8));
+        assert_eq!(local.start(), Ok(8));
+    }
+
+    #[test]
+    fn suspend_and_resume() {
+        let cell = cell(hart_state::STARTED);
+        let local = unsafe { cell.local() };
+        assert_eq!(local.suspend(), Ok(()));
+        assert_eq!(cell.remote().sbi_get_status(), hart_state::SUSPEND_PENDING);
+        assert_eq!(local.suspended(), Ok(()));
+        assert_eq!(cell.remote().sbi_get_status(), hart_state::SUSPENDED);
+        assert_eq!(local.resume(), Ok(()));
+        assert_eq!(cell.remote().sbi_get_status(), hart_state::RESUME_PENDING);
+        assert_eq!(local.resumed(), Ok(()));
+        assert_eq!(cell.remote().sbi_get_status(), hart_state::STARTED);
+    }
+
+    #[test]
+    fn every_legal_transit() {
+        for (name, transit, from, to) in transits() {
+            let cell = cell(from);
+            assert_eq!(
+                transit(&unsafe { cell.local() }),
+                Ok(()),
+                "{name} from {from}"
+            );
+            assert_eq!(cell.remote().sbi_get_status(), to, "{name} from {from}");
+        }
+    }
+
+    #[test]
+    fn reject_illegal_transit() {
+        for (name, transit, legal, _) in transits() {
+            for from in STATES.into_iter().filter(|s| *s != legal) {
+                let cell = cell(from);
+                assert_eq!(
+                    transit(&unsafe { cell.local() }),
+                    Err(from),
+                    "{name} from {from}"
+                );
+                assert_eq!(cell.remote().sbi_get_status(), from, "{name} from {from}");
+            }
+        }
+    }
+
+    #[test]
+    fn report_start_in_progress_as_pending() {
+        let cell = cell(HART_STATE_START_PENDING_EXT);
+        assert_eq!(cell.remote().sbi_get_status(), hart_state::START_PENDING);
+        assert!(!cell.remote().start(7));
+        assert!(!cell.remote().allow_ipi());
+        for (name, transit, _, _) in transits() {
+            assert_eq!(
+                transit(&unsafe { cell.local() }),
+                Err(hart_state::START_PENDING),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn allow_ipi_while_running_or_suspended() {
+        for status in STATES {
+            let allow = matches!(
+                status,
+                hart_state::STARTED
+                    | hart_state::SUSPEND_PENDING
+                    | hart_state::SUSPENDED
+                    | hart_state::RESUME_PENDING
+            );
+            assert_eq!(cell(status).remote().allow_ipi(), allow, "{status}");
+        }
+    }
+}
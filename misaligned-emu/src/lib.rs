@@ -0,0 +1,382 @@
+//! 非对齐访存的译码和模拟。
+//!
+//! 按 RV64 译码，支持整数和浮点的读写，包括压缩指令。
+//! 硬件线程的寄存器和访存通过 [`Hart`] 抽象，因此可以在宿主机上测试。
+
+#![no_std]
+#![deny(warnings, missing_docs)]
+
+/// 访存指令使用的寄存器。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Reg {
+    /// 整数寄存器。
+    X(u8),
+    /// 浮点寄存器。
+    F(u8),
+}
+
+/// 访存类型。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+    /// 读。`signed` 表示整数结果要符号扩展。
+    Load {
+        /// 符号扩展。
+        signed: bool,
+    },
+    /// 写。
+    Store,
+}
+
+/// 译码得到的访存指令。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Access {
+    /// 访存类型。
+    pub kind: Kind,
+    /// 访存宽度，单位是字节。
+    pub width: usize,
+    /// 读的目标寄存器或写的源寄存器。
+    pub reg: Reg,
+    /// 指令长度，单位是字节。
+    pub len: usize,
+}
+
+/// 陷入前的硬件线程。
+pub trait Hart {
+    /// 访存失败的原因。
+    type Fault;
+
+    /// 读整数寄存器 `x{i}`，`i` 不为 0。
+    fn x(&self, i: u8) -> usize;
+
+    /// 写整数寄存器 `x{i}`，`i` 不为 0。
+    fn set_x(&mut self, i: u8, val: usize);
+
+    /// 读浮点寄存器 `f{i}` 的全部 64 位。
+    fn f(&self, i: u8) -> u64;
+
+    /// 写浮点寄存器 `f{i}` 的全部 64 位。
+    fn set_f(&mut self, i: u8, val: u64);
+
+    /// 以陷入前的特权级从 `addr` 取 16 位指令。
+    fn fetch_u16(&mut self, addr: usize) -> Result<u16, Self::Fault>;
+
+    /// 以陷入前的特权级从 `addr` 读一个字节。
+    fn load_u8(&mut self, addr: usize) -> Result<u8, Self::Fault>;
+
+    /// 以陷入前的特权级向 `addr` 写一个字节。
+    fn store_u8(&mut self, addr: usize, val: u8) -> Result<(), Self::Fault>;
+}
+
+/// 模拟失败。
+#[derive(PartialEq, Eq, Debug)]
+pub enum Error<F> {
+    /// 不是能模拟的访存指令。
+    Unsupported(u32),
+    /// 取指令或访存失败。
+    Fault(F),
+}
+
+/// 译码访存指令。
+pub fn decode(insn: u32) -> Option<Access> {
+    use {Kind::*, Reg::*};
+
+    let funct3 = (insn >> 12) & 7;
+    let rd = ((insn >> 7) & 0x1f) as u8;
+    let rs2 = ((insn >> 20) & 0x1f) as u8;
+    let (kind, width, reg, len) = match insn & 3 {
+        // 32 位指令
+        3 => {
+            let (kind, width, reg) = match (insn & 0x7f, funct3) {
+                (0x03, 0..=3) => (Load { signed: true }, 1 << funct3, X(rd)),
+                (0x03, 4..=6) => (Load { signed: false }, 1 << (funct3 - 4), X(rd)),
+                (0x07, 1..=3) => (Load { signed: false }, 1 << funct3, F(rd)),
+                (0x23, 0..=3) => (Store, 1 << funct3, X(rs2)),
+                (0x27, 1..=3) => (Store, 1 << funct3, F(rs2)),
+                _ => return None,
+            };
+            (kind, width, reg, 4)
+        }
+        // C0：rd' 和 rs2' 都在 [4:2]
+        0 => {
+            let r = ((insn >> 2) & 7) as u8 + 8;
+            let (kind, width, reg) = match (insn >> 13) & 7 {
+                0b001 => (Load { signed: false }, 8, F(r)),
+                0b010 => (Load { signed: true }, 4, X(r)),
+                0b011 => (Load { signed: true }, 8, X(r)),
+                0b101 => (Store, 8, F(r)),
+                0b110 => (Store, 4, X(r)),
+                0b111 => (Store, 8, X(r)),
+                _ => return None,
+            };
+            (kind, width, reg, 2)
+        }
+        // C2：以 sp 为基址，rd 在 [11:7]，rs2 在 [6:2]
+        2 => {
+            let rs2 = ((insn >> 2) & 0x1f) as u8;
+            let (kind, width, reg) = match (insn >> 13) & 7 {
+                0b001 => (Load { signed: false }, 8, F(rd)),
+                0b010 if rd != 0 => (Load { signed: true }, 4, X(rd)),
+                0b011 if rd != 0 => (Load { signed: true }, 8, X(rd)),
+                0b101 => (Store, 8, F(rs2)),
+                0b110 => (Store, 4, X(rs2)),
+                0b111 => (Store, 8, X(rs2)),
+                _ => return None,
+            };
+            (kind, width, reg, 2)
+        }
+        _ => return None,
+    };
+    Some(Access {
+        kind,
+        width,
+        reg,
+        len,
+    })
+}
+
+/// 取 `pc` 处的指令，压缩指令只有低 16 位。
+pub fn fetch<H: Hart>(hart: &mut H, pc: usize) -> Result<u32, H::Fault> {
+    let lo = hart.fetch_u16(pc)? as u32;
+    if lo & 3 == 3 {
+        let hi = hart.fetch_u16(pc + 2)? as u32;
+        Ok(lo | hi << 16)
+    } else {
+        Ok(lo)
+    }
+}
+
+/// 逐字节模拟 `pc` 处的指令对 `addr` 的访存，返回指令长度。
+///
+/// `store` 表示陷入是写引起的，与指令不符时返回 [`Error::Unsupported`]。
+pub fn emulate<H: Hart>(
+    hart: &mut H,
+    pc: usize,
+    addr: usize,
+    store: bool,
+) -> Result<usize, Error<H::Fault>> {
+    let insn = fetch(hart, pc).map_err(Error::Fault)?;
+    let access = match decode(insn) {
+        Some(access) if matches!(access.kind, Kind::Store) == store => access,
+        _ => return Err(Error::Unsupported(insn)),
+    };
+    let bits = access.width * 8;
+    match access.kind {
+        Kind::Load { signed } => {
+            let mut val = 0u64;
+            for i in 0..access.width {
+                val |= (hart.load_u8(addr + i).map_err(Error::Fault)? as u64) << (i * 8);
+            }
+            match access.reg {
+                Reg::X(0) => {}
+                Reg::X(i) => hart.set_x(i, extend(val, bits, signed) as usize),
+                // 窄于 64 位的浮点数高位填 1
+                Reg::F(i) => hart.set_f(i, extend(val, bits, false) | !mask(bits)),
+            }
+        }
+        Kind::Store => {
+            let val = match access.reg {
+                Reg::X(0) => 0,
+                Reg::X(i) => hart.x(i) as u64,
+                Reg::F(i) => hart.f(i),
+            };
+            for i in 0..access.width {
+                hart.store_u8(addr + i, (val >> (i * 8)) as u8)
+                    .map_err(Error::Fault)?;
+            }
+        }
+    }
+    Ok(access.len)
+}
+
+/// 低 `bits` 位全 1。
+#[inline]
+const fn mask(bits: usize) -> u64 {
+    if bits >= 64 {
+        !0
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+/// 将低 `bits` 位扩展到 64 位。
+#[inline]
+const fn extend(val: u64, bits: usize, signed: bool) -> u64 {
+    if bits >= 64 {
+        val
+    } else if signed {
+        (((val << (64 - bits)) as i64) >> (64 - bits)) as u64
+    } else {
+        val & mask(bits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: usize = 0x8020_0000;
+    const PC: usize = BASE;
+    const DATA: usize = BASE + 0x40;
+
+    /// 一段内存和一组寄存器。
+    struct Mock {
+        x: [usize; 32],
+        f: [u64; 32],
+        mem: [u8; 0x80],
+    }
+
+    impl Mock {
+        fn new(insn: &[u8]) -> Self {
+            let mut mem = [0u8; 0x80];
+            mem[..insn.len()].copy_from_slice(insn);
+            Self {
+                x: [0; 32],
+                f: [0; 32],
+                mem,
+            }
+        }
+
+        fn index(&self, addr: usize) -> Result<usize, usize> {
+            addr.checked_sub(BASE)
+                .filter(|i| *i < self.mem.len())
+                .ok_or(addr)
+        }
+    }
+
+    impl Hart for Mock {
+        type Fault = usize;
+
+        fn x(&self, i: u8) -> usize {
+            self.x[i as usize]
+        }
+
+        fn set_x(&mut self, i: u8, val: usize) {
+            self.x[i as usize] = val;
+        }
+
+        fn f(&self, i: u8) -> u64 {
+            self.f[i as usize]
+        }
+
+        fn set_f(&mut self, i: u8, val: u64) {
+            self.f[i as usize] = val;
+        }
+
+        fn fetch_u16(&mut self, addr: usize) -> Result<u16, usize> {
+            let i = self.index(addr)?;
+            Ok(u16::from_le_bytes([self.mem[i], self.mem[i + 1]]))
+        }
+
+        fn load_u8(&mut self, addr: usize) -> Result<u8, usize> {
+            self.index(addr).map(|i| self.mem[i])
+        }
+
+        fn store_u8(&mut self, addr: usize, val: u8) -> Result<(), usize> {
+            let i = self.index(addr)?;
+            self.mem[i] = val;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn load_word_sign_extends() {
+        // lw a0, 1(a1)
+        let mut hart = Mock::new(&0x0015_a503u32.to_le_bytes());
+        hart.mem[0x41..0x45].copy_from_slice(&0x8765_4321u32.to_le_bytes());
+        assert_eq!(emulate(&mut hart, PC, DATA + 1, false), Ok(4));
+        assert_eq!(hart.x[10], 0xffff_ffff_8765_4321);
+    }
+
+    #[test]
+    fn load_unsigned_half() {
+        // lhu t0, 3(a1)
+        let mut hart = Mock::new(&0x0035_d283u32.to_le_bytes());
+        hart.mem[0x43..0x45].copy_from_slice(&0x8001u16.to_le_bytes());
+        assert_eq!(emulate(&mut hart, PC, DATA + 3, false), Ok(4));
+        assert_eq!(hart.x[5], 0x8001);
+    }
+
+    #[test]
+    fn compressed_store_double_from_sp() {
+        // c.sdsp s1, 8(sp)
+        let mut hart = Mock::new(&0xe426u16.to_le_bytes());
+        hart.x[9] = 0x0123_4567_89ab_cdef;
+        assert_eq!(emulate(&mut hart, PC, DATA + 5, true), Ok(2));
+        assert_eq!(
+            hart.mem[0x45..0x4d],
+            0x0123_4567_89ab_cdef_u64.to_le_bytes()
+        );
+    }
+
+    #[test]
+    fn compressed_load_word() {
+        // c.lw a5, 0(a0)
+        let mut hart = Mock::new(&0x411cu16.to_le_bytes());
+        hart.mem[0x42..0x46].copy_from_slice(&0x7fff_fffeu32.to_le_bytes());
+        assert_eq!(emulate(&mut hart, PC, DATA + 2, false), Ok(2));
+        assert_eq!(hart.x[15], 0x7fff_fffe);
+    }
+
+    #[test]
+    fn float_loads_are_nan_boxed() {
+        // flw fa0, 0(a0)
+        let mut hart = Mock::new(&0x0005_2507u32.to_le_bytes());
+        hart.mem[0x41..0x45].copy_from_slice(&1.0f32.to_bits().to_le_bytes());
+        assert_eq!(emulate(&mut hart, PC, DATA + 1, false), Ok(4));
+        assert_eq!(hart.f[10], 0xffff_ffff_0000_0000 | 1.0f32.to_bits() as u64);
+    }
+
+    #[test]
+    fn compressed_float_store_double() {
+        // c.fsd fa1, 0(a0)
+        let mut hart = Mock::new(&0xa10cu16.to_le_bytes());
+        hart.f[11] = 2.5f64.to_bits();
+        assert_eq!(emulate(&mut hart, PC, DATA + 3, true), Ok(2));
+        assert_eq!(hart.mem[0x43..0x4b], 2.5f64.to_bits().to_le_bytes());
+    }
+
+    #[test]
+    fn load_into_zero_is_discarded() {
+        // ld zero, 0(a0)
+        let mut hart = Mock::new(&0x0005_3003u32.to_le_bytes());
+        assert_eq!(emulate(&mut hart, PC, DATA + 1, false), Ok(4));
+        assert_eq!(hart.x, [0; 32]);
+    }
+
+    #[test]
+    fn kind_must_match_trap() {
+        // sw a0, 0(a1)
+        let insn = 0x00a5_a023u32;
+        let mut hart = Mock::new(&insn.to_le_bytes());
+        assert_eq!(
+            emulate(&mut hart, PC, DATA + 1, false),
+            Err(Error::Unsupported(insn))
+        );
+    }
+
+    #[test]
+    fn faults_are_reported() {
+        // sd a0, 0(a1)
+        let mut hart = Mock::new(&0x00a5_b023u32.to_le_bytes());
+        let addr = BASE + 0x7d;
+        assert_eq!(
+            emulate(&mut hart, PC, addr, true),
+            Err(Error::Fault(BASE + 0x80))
+        );
+        // 取指令失败
+        assert_eq!(
+            emulate(&mut hart, BASE - 2, addr, true),
+            Err(Error::Fault(BASE - 2))
+        );
+    }
+
+    #[test]
+    fn non_memory_instructions_are_rejected() {
+        // addi a0, a0, 1
+        assert_eq!(decode(0x0015_0513), None);
+        // c.addi a0, 1
+        assert_eq!(decode(0x0505), None);
+        // c.lwsp zero, 0(sp) 是保留编码
+        assert_eq!(decode(0x4002), None);
+    }
+}
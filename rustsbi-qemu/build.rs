@@ -5,6 +5,19 @@ fn main() {
     fs::write(ld, LINKER).unwrap();
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-env-changed=LOG");
+    println!("cargo:rerun-if-env-changed=HART_REPORT");
+    println!("cargo:rerun-if-env-changed=STACK_SIZE");
+    // 每个硬件线程的栈空间，单位为字节，默认 16 KiB
+    let stack_size = env::var("STACK_SIZE").map_or(16 * 1024, |s| {
+        s.parse::<usize>()
+            .unwrap_or_else(|_| panic!("STACK_SIZE must be a number of bytes, got {s:?}"))
+    });
+    // 这里只检查格式，栈能否容纳硬件线程状态、保护区和陷入处理由 `trap_stack` 在编译期检查
+    assert!(
+        stack_size >= 4096 && stack_size % 128 == 0,
+        "STACK_SIZE must be a multiple of 128 and at least 4096, got {stack_size}"
+    );
+    println!("cargo:rustc-env=LEN_STACK_PER_HART={stack_size}");
     println!("cargo:rustc-link-arg=-T{}", ld.display());
 }
 
@@ -18,6 +31,8 @@ SECTIONS {
     .text : {
         *(.text.entry)
         *(.text .text.*)
+        . = ALIGN(4K);
+        etext = .;
     } > DRAM
     .rodata : {
         *(.rodata .rodata.*)
@@ -36,6 +51,9 @@ SECTIONS {
         . = ALIGN(8);
         ebss = .;
     } > DRAM
+    . = ALIGN(128);
+    sstack = .;
+    estack = ORIGIN(DRAM) + LENGTH(DRAM);
     /DISCARD/ : {
         *(.eh_frame)
     }
@@ -0,0 +1,220 @@
+//! 高级中断架构（AIA）。
+//!
+//! 每个插槽的 M 态 APLIC 根域把所有中断源委托给 S 态域，并为 S 态域配置 MSI 地址。
+//! 存在 M 态 IMSIC 时，核间中断以 MSI 的形式写入目标硬件线程的 M 态中断文件。
+//!
+//! 多插槽时中断文件分组，每组一个寄存器区域，MSI 地址按组号和组内序号拼成。
+
+use crate::device_tree::{Aplic, BoardInfo, Imsic};
+use core::arch::asm;
+use rcore_console::log;
+use spin::Once;
+
+/// APLIC 寄存器偏移。
+mod aplic {
+    pub const DOMAINCFG: usize = 0x0000;
+    pub const SOURCECFG: usize = 0x0004;
+    pub const MMSIADDRCFG: usize = 0x1bc0;
+    pub const MMSIADDRCFGH: usize = 0x1bc4;
+    pub const SMSIADDRCFG: usize = 0x1bc8;
+    pub const SMSIADDRCFGH: usize = 0x1bcc;
+
+    pub const DOMAINCFG_IE: u32 = 1 << 8;
+    pub const DOMAINCFG_DM: u32 = 1 << 2;
+    /// 委托给子域，低位是子域序号。
+    pub const SOURCECFG_D: u32 = 1 << 10;
+}
+
+const CSR_MISELECT: usize = 0x350;
+const CSR_MIREG: usize = 0x351;
+const CSR_MTOPEI: usize = 0x35c;
+
+/// 中断文件间接访问寄存器。
+const EIDELIVERY: usize = 0x70;
+const EITHRESHOLD: usize = 0x72;
+const EIP0: usize = 0x80;
+const EIE0: usize = 0xc0;
+
+/// 核间中断使用的中断号。
+const IPI_ID: usize = 1;
+/// 中断文件每页 4 KiB。
+const IMSIC_PAGE_BITS: usize = 12;
+
+static IMSIC: Once<Imsic> = Once::new();
+
+/// 初始化中断控制器。
+pub(crate) fn init(board_info: &BoardInfo) {
+    let imsic_m = board_info.imsic_m.as_ref();
+    let imsic_s = board_info.imsic_s.as_ref();
+    for aplic in board_info.aplic_m.iter() {
+        init_aplic(aplic, imsic_m, imsic_s);
+    }
+    if let Some(imsic) = imsic_m {
+        IMSIC.call_once(|| imsic.clone());
+    }
+    for aplic in board_info.aplic_s.iter() {
+        log::info!(
+            "APLIC S-level domain at {:#x?}, {} sources delegated",
+            aplic.range,
+            aplic.num_sources,
+        );
+    }
+    for range in imsic_s.iter().flat_map(|imsic| imsic.regs.iter()) {
+        log::info!("IMSIC S-level files at {range:#x?}");
+    }
+}
+
+/// 初始化当前硬件线程的 M 态中断文件。
+///
+/// S 态访问 AIA 状态由 [`envcfg::init_hart`](crate::envcfg::init_hart) 在 `mstateen0` 中允许。
+pub(crate) fn init_hart() {
+    if IMSIC.get().is_some() {
+        write_ireg(EIDELIVERY, 1);
+        write_ireg(EITHRESHOLD, 0);
+        write_ireg(EIP0, 0);
+        write_ireg(EIE0, 1 << IPI_ID);
+    }
+}
+
+/// 是否用 IMSIC 发送核间中断。
+#[inline]
+pub(crate) fn has_imsic() -> bool {
+    IMSIC.get().is_some()
+}
+
+/// 通过 MSI 向 `hart_idx` 发送核间中断，没有 M 态中断文件时返回 `false`。
+#[inline]
+pub(crate) fn send_ipi(hart_idx: usize) -> bool {
+    match IMSIC.get().and_then(|imsic| file(imsic, hart_idx)) {
+        Some(file) => {
+            // seteipnum_le
+            unsafe { (file as *mut u32).write_volatile(IPI_ID as _) };
+            true
+        }
+        None => false,
+    }
+}
+
+/// 取走当前硬件线程 M 态中断文件中所有待处理的中断。
+#[inline]
+pub(crate) fn clear_ipi() {
+    if IMSIC.get().is_some() {
+        loop {
+            let topei: usize;
+            unsafe { asm!("csrrw {}, {csr}, zero", out(reg) topei, csr = const CSR_MTOPEI) };
+            if topei == 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// 委托根域的所有中断源给 S 态域。
+///
+/// `imsic_m` 和 `imsic_s` 分别决定 M 态和 S 态域 MSI 的目标地址。
+fn init_aplic(aplic: &Aplic, imsic_m: Option<&Imsic>, imsic_s: Option<&Imsic>) {
+    use aplic::*;
+    let base = aplic.range.start;
+    let write =
+        |offset: usize, val: u32| unsafe { ((base + offset) as *mut u32).write_volatile(val) };
+    write(DOMAINCFG, 0);
+    for i in 1..=aplic.num_sources {
+        write(SOURCECFG + (i - 1) * 4, SOURCECFG_D);
+    }
+    if !aplic.msi {
+        write(DOMAINCFG, DOMAINCFG_IE);
+        return;
+    }
+    // 硬件线程序号的低 LHXW 位是组内序号，高 HHXW 位是组号，组号位于地址的第 HHXS + 24 位。
+    // 这几个字段只在 mmsiaddrcfgh 中，M 态和 S 态共用
+    let (lhxw, hhxw, hhxs) = imsic_m.or(imsic_s).map_or((0, 0, 0), |imsic| {
+        let lhxw = ceil_log2(harts_per_group(imsic)) as u32;
+        match imsic.group_bits {
+            0 => (lhxw, 0, 0),
+            bits => (
+                lhxw,
+                bits as u32,
+                imsic.group_shift.saturating_sub(2 * IMSIC_PAGE_BITS) as u32,
+            ),
+        }
+    });
+    let ppn = imsic_m.map_or(0, base_ppn);
+    write(MMSIADDRCFG, ppn as u32);
+    write(
+        MMSIADDRCFGH,
+        ((ppn >> 32) as u32 & 0xfff)
+            | lhxw << 12
+            | hhxw << 16
+            | imsic_m.map_or(0, |imsic| imsic.guest_bits as u32) << 20
+            | hhxs << 24,
+    );
+    if let Some(imsic) = imsic_s {
+        let ppn = base_ppn(imsic);
+        write(SMSIADDRCFG, ppn as u32);
+        write(
+            SMSIADDRCFGH,
+            ((ppn >> 32) as u32 & 0xfff) | (imsic.guest_bits as u32) << 20,
+        );
+    }
+    write(DOMAINCFG, DOMAINCFG_IE | DOMAINCFG_DM);
+}
+
+/// 第 `i` 个中断文件的地址，中断文件依次排列在各组的寄存器区域中。
+fn file(imsic: &Imsic, i: usize) -> Option<usize> {
+    if i >= imsic.harts {
+        return None;
+    }
+    let mut offset = i << (imsic.guest_bits + IMSIC_PAGE_BITS);
+    for range in imsic.regs.iter() {
+        if offset < range.len() {
+            return Some(range.start + offset);
+        }
+        offset -= range.len();
+    }
+    None
+}
+
+/// 各组中最多的中断文件数。
+fn harts_per_group(imsic: &Imsic) -> usize {
+    imsic
+        .regs
+        .iter()
+        .map(|range| range.len() >> (imsic.guest_bits + IMSIC_PAGE_BITS))
+        .max()
+        .unwrap_or(0)
+}
+
+/// 第 0 组的页号，组号和组内序号都为 0 时 MSI 的目标。
+fn base_ppn(imsic: &Imsic) -> usize {
+    imsic
+        .regs
+        .iter()
+        .map(|range| range.start)
+        .min()
+        .unwrap_or(0)
+        >> IMSIC_PAGE_BITS
+}
+
+/// 写 M 态中断文件的间接访问寄存器。
+#[inline]
+fn write_ireg(select: usize, val: usize) {
+    unsafe {
+        asm!(
+            "csrw {miselect}, {select}",
+            "csrw {mireg}, {val}",
+            select   = in(reg) select,
+            val      = in(reg) val,
+            miselect = const CSR_MISELECT,
+            mireg    = const CSR_MIREG,
+        )
+    };
+}
+
+#[inline]
+const fn ceil_log2(n: usize) -> usize {
+    if n <= 1 {
+        0
+    } else {
+        (usize::BITS - (n - 1).leading_zeros()) as _
+    }
+}
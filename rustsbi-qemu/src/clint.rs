@@ -1,25 +1,74 @@
-use crate::{hart_id, trap_stack::remote_hsm};
-use aclint::SifiveClint;
+use crate::{
+    aia,
+    device_tree::{BoardInfo, HartLocal},
+    envcfg::{menvcfg, CSR_MENVCFG},
+    hart_id, pmu,
+    trap_stack::{local_ipi, local_sstc, remote_hsm, remote_ipi},
+    BOARD_INFO, NUM_HART_MAX,
+};
 use core::{
+    arch::asm,
     ptr::null_mut,
-    sync::atomic::{AtomicPtr, Ordering},
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
 };
 use rustsbi::{HartMask, Ipi, SbiRet, Timer};
+use sbi_spec::pmu::firmware_event;
 
 pub(crate) struct Clint;
 
-pub(crate) static CLINT: AtomicPtr<SifiveClint> = AtomicPtr::new(null_mut());
+/// 每个硬件线程的核心本地中断寄存器，以 hartid 为下标。
+///
+/// `mtimer` 中断代理也从这里找到当前硬件线程的 `mtimecmp`。
+pub(crate) static HARTS: AtomicPtr<HartLocal> = AtomicPtr::new(null_mut());
+/// `mtime` 寄存器地址。
+static MTIME: AtomicUsize = AtomicUsize::new(0);
+
+/// 核间中断类型：转发给特权软件的软件中断。
+pub(crate) const IPI_TYPE_SSOFT: usize = 1 << 0;
+/// 核间中断类型：远程屏障请求。
+pub(crate) const IPI_TYPE_FENCE: usize = 1 << 1;
+/// 核间中断类型：热重启，停在固件里等待。
+pub(crate) const IPI_TYPE_REBOOT: usize = 1 << 2;
+
+const CSR_STIMECMP: usize = 0x14d;
 
-pub(crate) fn init(base: usize) {
-    CLINT.store(base as _, Ordering::Release);
+/// 记录设备树中找到的 CLINT 和 ACLINT 寄存器。
+pub(crate) fn init(board_info: &'static BoardInfo) {
+    MTIME.store(board_info.mtime, Ordering::Relaxed);
+    // 其他硬件线程等待寄存器地址设置后才访问这些设备
+    HARTS.store(board_info.harts.as_ptr() as _, Ordering::Release);
+}
+
+/// 探测当前硬件线程是否启用了 Sstc 扩展。
+///
+/// 支持 Sstc 时特权软件的定时器中断由 `stimecmp` 直接产生，不再经过 M 态。
+/// `menvcfg.STCE` 由 [`envcfg::init_hart`](crate::envcfg::init_hart) 设置。
+pub(crate) fn init_hart() {
+    let sstc = try_read_csr!(CSR_MENVCFG).map_or(false, |bits| bits & menvcfg::STCE != 0)
+        && try_write_csr!(CSR_STIMECMP, u64::MAX);
+    *local_sstc() = sstc;
+}
+
+/// 当前硬件线程是否用 `stimecmp` 实现定时器。
+#[inline]
+pub(crate) fn has_sstc() -> bool {
+    *local_sstc()
 }
 
 impl Ipi for Clint {
     #[inline]
     fn send_ipi(&self, hart_mask: HartMask) -> SbiRet {
-        for i in 0..crate::NUM_HART_MAX {
-            if hart_mask.has_bit(i) && remote_hsm(i).map_or(false, |hsm| hsm.allow_ipi()) {
-                set_msip(i);
+        for i in BOARD_INFO.wait().harts_in(hart_mask) {
+            if remote_hsm(i).map_or(false, |hsm| hsm.allow_ipi()) {
+                pmu::record(firmware_event::IPI_SENT);
+                // 有 SSWI 时直接设置目标的 sip.SSIP，目标不必进入 M 态；
+                // 发送方仍要陷入固件，特权软件也可以按设备树直接使用 SSWI
+                match harts()[i].setssip {
+                    0 => {
+                        send_ipi_typed(i, IPI_TYPE_SSOFT);
+                    }
+                    setssip => unsafe { write_u32(setssip, 1) },
+                }
             }
         }
         SbiRet::success(0)
@@ -29,32 +78,106 @@ impl Ipi for Clint {
 impl Timer for Clint {
     #[inline]
     fn set_timer(&self, time_value: u64) {
+        pmu::record(firmware_event::SET_TIMER);
+        if has_sstc() {
+            // mip.STIP 由 stimecmp 和 time 比较产生
+            unsafe { asm!("csrw {csr}, {}", in(reg) time_value, csr = const CSR_STIMECMP) };
+            return;
+        }
         unsafe {
             riscv::register::mip::clear_stimer();
-            (*CLINT.load(Ordering::Relaxed)).write_mtimecmp(hart_id(), time_value);
+            write_mtimecmp(hart_id(), time_value);
         }
     }
 }
 
+/// 如果 M 态定时器中断到期，转为特权软件的定时器中断。
+///
+/// 在屏蔽 M 态中断时代替 `mtimer` 中断代理。
 #[inline]
-pub fn set_msip(hart_idx: usize) {
-    unsafe { &*CLINT.load(Ordering::Relaxed) }.set_msip(hart_idx);
+pub(crate) fn forward_timer() {
+    use riscv::register::mip;
+    if mip::read().mtimer() {
+        unsafe {
+            write_mtimecmp(hart_id(), u64::MAX);
+            mip::set_stimer();
+        }
+    }
+}
+
+/// 读 `mtime`。
+#[inline]
+pub(crate) fn mtime() -> u64 {
+    unsafe { (MTIME.load(Ordering::Relaxed) as *const u64).read_volatile() }
+}
+
+/// 唤醒 `hart_idx`，有 M 态中断文件时发送 MSI，否则设置 msip。
+#[inline]
+pub(crate) fn wake(hart_idx: usize) {
+    if !aia::send_ipi(hart_idx) {
+        set_msip(hart_idx);
+    }
 }
 
+/// 标记 `ipi_type` 类型的核间中断并向 `hart_idx` 发送。
+///
+/// 目标没有分配到栈，不能处理核间中断时返回 `false`。
 #[inline]
-pub fn clear_msip() {
-    unsafe { &*CLINT.load(Ordering::Relaxed) }.clear_msip(hart_id());
+pub(crate) fn send_ipi_typed(hart_idx: usize, ipi_type: usize) -> bool {
+    if let Some(pending) = remote_ipi(hart_idx) {
+        pending.fetch_or(ipi_type, Ordering::AcqRel);
+        wake(hart_idx);
+        true
+    } else {
+        false
+    }
 }
 
+/// 清除当前硬件线程的 msip 和 MSI，并取出所有待处理的核间中断类型。
+#[inline]
+pub(crate) fn take_ipi() -> usize {
+    clear_msip(hart_id());
+    aia::clear_ipi();
+    local_ipi().swap(0, Ordering::AcqRel)
+}
+
+/// 等待设备地址设置，然后清除当前硬件线程的 msip 和 mtimecmp。
 #[inline]
 pub fn clear() {
-    loop {
-        if let Some(clint) = unsafe { CLINT.load(Ordering::Relaxed).as_ref() } {
-            clint.clear_msip(hart_id());
-            clint.write_mtimecmp(hart_id(), u64::MAX);
-            break;
-        } else {
-            continue;
-        }
+    while HARTS.load(Ordering::Acquire).is_null() {
+        core::hint::spin_loop();
+    }
+    clear_msip(hart_id());
+    unsafe { write_mtimecmp(hart_id(), u64::MAX) };
+}
+
+#[inline]
+fn harts() -> &'static [HartLocal] {
+    unsafe { core::slice::from_raw_parts(HARTS.load(Ordering::Relaxed), NUM_HART_MAX) }
+}
+
+#[inline]
+fn set_msip(hart_idx: usize) {
+    match harts().get(hart_idx).map_or(0, |hart| hart.msip) {
+        0 => {}
+        msip => unsafe { write_u32(msip, 1) },
     }
 }
+
+#[inline]
+fn clear_msip(hart_idx: usize) {
+    match harts()[hart_idx].msip {
+        0 => {}
+        msip => unsafe { write_u32(msip, 0) },
+    }
+}
+
+#[inline]
+unsafe fn write_mtimecmp(hart_idx: usize, val: u64) {
+    (harts()[hart_idx].mtimecmp as *mut u64).write_volatile(val);
+}
+
+#[inline]
+unsafe fn write_u32(addr: usize, val: u32) {
+    (addr as *mut u32).write_volatile(val);
+}
@@ -0,0 +1,180 @@
+//! 固件控制台。
+//!
+//! 每个硬件线程把输出写进自己的行缓冲，一行写完或缓冲写满时交付。
+//! 同一时刻只有一个硬件线程把所有交付的行写到串口，其他硬件线程交付后直接返回。
+//! 因此不同硬件线程的输出以行为单位交错，慢速的串口也不会阻塞其他硬件线程。
+
+use crate::{hart_id, uart16550, NUM_HART_MAX};
+use core::{
+    cell::UnsafeCell,
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+/// 行缓冲的长度，更长的行分段交付。
+const LINE_LEN: usize = 128;
+/// 每个硬件线程的行缓冲数，一行等待输出时可以继续写下一行。
+const LINES_PER_HART: usize = 2;
+
+/// 每个硬件线程的行缓冲，以 hartid 为下标。
+static HARTS: [HartLines; NUM_HART_MAX] = [HartLines::EMPTY; NUM_HART_MAX];
+/// 已交付、尚未输出的行数。
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+/// 有硬件线程正在向串口输出。
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+pub(crate) struct Console;
+
+impl rcore_console::Console for Console {
+    #[inline]
+    fn put_char(&self, c: u8) {
+        write(&[c]);
+    }
+
+    #[inline]
+    fn put_str(&self, s: &str) {
+        write(s.as_bytes());
+    }
+}
+
+/// 把 `bytes` 写进当前硬件线程的行缓冲。
+pub(crate) fn write(bytes: &[u8]) {
+    let hart = &HARTS[hart_id()];
+    for &c in bytes {
+        let line = hart.writing();
+        let len = line.push(c);
+        if c == b'\n' || len == LINE_LEN {
+            hart.submit();
+        }
+    }
+    drain();
+}
+
+/// 交付当前硬件线程未写完的行，并尝试输出。
+///
+/// 特权软件的输出不一定以换行结尾，每个写控制台的 SBI 调用结束前都要调用。
+pub(crate) fn flush() {
+    let hart = &HARTS[hart_id()];
+    if hart.writing().len() != 0 {
+        hart.submit();
+    }
+    drain();
+}
+
+/// 如果没有其他硬件线程正在输出，输出所有交付的行。
+fn drain() {
+    while PENDING.load(Ordering::Acquire) != 0 {
+        if DRAINING.swap(true, Ordering::Acquire) {
+            return;
+        }
+        for hart in &HARTS {
+            while let Some(line) = hart.reading() {
+                let uart = uart16550::UART.lock();
+                let mut bytes = line.bytes();
+                while !bytes.is_empty() {
+                    let count = uart.get().write(bytes);
+                    bytes = &bytes[count..];
+                }
+                drop(uart);
+                hart.release();
+            }
+        }
+        // 释放后再检查一次，输出期间交付的行不会被遗漏
+        DRAINING.store(false, Ordering::Release);
+    }
+}
+
+/// 一个硬件线程的行缓冲。
+struct HartLines {
+    lines: [Line; LINES_PER_HART],
+    /// 正在写的行，只由所属的硬件线程访问。
+    write: AtomicUsize,
+    /// 下一个要输出的行，只由正在输出的硬件线程访问。
+    read: AtomicUsize,
+}
+
+impl HartLines {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Self = Self {
+        lines: [Line::EMPTY; LINES_PER_HART],
+        write: AtomicUsize::new(0),
+        read: AtomicUsize::new(0),
+    };
+
+    /// 取出正在写的行，这一行还在等待输出时帮忙输出。
+    fn writing(&self) -> &Line {
+        let line = &self.lines[self.write.load(Ordering::Relaxed)];
+        while line.ready.load(Ordering::Acquire) {
+            drain();
+            spin_loop();
+        }
+        line
+    }
+
+    /// 交付正在写的行。
+    fn submit(&self) {
+        let i = self.write.load(Ordering::Relaxed);
+        self.lines[i].ready.store(true, Ordering::Release);
+        self.write
+            .store((i + 1) % LINES_PER_HART, Ordering::Relaxed);
+        PENDING.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// 取出下一个要输出的行。
+    fn reading(&self) -> Option<&Line> {
+        let line = &self.lines[self.read.load(Ordering::Relaxed)];
+        line.ready.load(Ordering::Acquire).then_some(line)
+    }
+
+    /// 输出完成，清空这一行。
+    fn release(&self) {
+        let i = self.read.load(Ordering::Relaxed);
+        let line = &self.lines[i];
+        unsafe { *line.len.get() = 0 };
+        line.ready.store(false, Ordering::Release);
+        self.read.store((i + 1) % LINES_PER_HART, Ordering::Relaxed);
+        PENDING.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// 一行输出。
+///
+/// `ready` 为 `false` 时只由所属的硬件线程写，为 `true` 时只由正在输出的硬件线程读。
+struct Line {
+    ready: AtomicBool,
+    len: UnsafeCell<usize>,
+    buf: UnsafeCell<[u8; LINE_LEN]>,
+}
+
+unsafe impl Sync for Line {}
+
+impl Line {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Self = Self {
+        ready: AtomicBool::new(false),
+        len: UnsafeCell::new(0),
+        buf: UnsafeCell::new([0; LINE_LEN]),
+    };
+
+    #[inline]
+    fn len(&self) -> usize {
+        unsafe { *self.len.get() }
+    }
+
+    /// 追加一个字节，返回追加后的长度。
+    #[inline]
+    fn push(&self, c: u8) -> usize {
+        unsafe {
+            let len = &mut *self.len.get();
+            (*self.buf.get())[*len] = c;
+            *len += 1;
+            *len
+        }
+    }
+
+    #[inline]
+    fn bytes(&self) -> &[u8] {
+        let buf = unsafe { &*self.buf.get() };
+        &buf[..self.len()]
+    }
+}
@@ -1,4 +1,4 @@
-﻿use crate::uart16550;
+﻿use crate::{console, pmp, uart16550};
 use core::ops::Range;
 use rustsbi::{Console, Physical, SbiRet};
 use spin::Once;
@@ -20,8 +20,11 @@ impl Console for DBCN {
         let start = bytes.phys_addr_lo();
         let end = start + bytes.num_bytes();
         if self.0.contains(&start) && self.0.contains(&(end - 1)) {
+            let _window = pmp::map_supervisor(start..end);
             let buf = unsafe { core::slice::from_raw_parts(start as *const u8, bytes.num_bytes()) };
-            SbiRet::success(uart16550::UART.lock().get().write(buf))
+            console::write(buf);
+            console::flush();
+            SbiRet::success(buf.len())
         } else {
             SbiRet::invalid_param()
         }
@@ -31,9 +34,12 @@ impl Console for DBCN {
         let start = bytes.phys_addr_lo();
         let end = start + bytes.num_bytes();
         if self.0.contains(&start) && self.0.contains(&(end - 1)) {
+            let _window = pmp::map_supervisor(start..end);
             let buf =
                 unsafe { core::slice::from_raw_parts_mut(start as *mut u8, bytes.num_bytes()) };
-            SbiRet::success(uart16550::UART.lock().get().read(buf))
+            // 读之前输出提示符等未写完的行
+            console::flush();
+            SbiRet::success(uart16550::read(buf))
         } else {
             SbiRet::invalid_param()
         }
@@ -41,11 +47,8 @@ impl Console for DBCN {
 
     #[inline]
     fn write_byte(&self, byte: u8) -> SbiRet {
-        let uart = uart16550::UART.lock();
-        loop {
-            if uart.get().write(&[byte]) == 1 {
-                return SbiRet::success(0);
-            }
-        }
+        console::write(&[byte]);
+        console::flush();
+        SbiRet::success(0)
     }
 }
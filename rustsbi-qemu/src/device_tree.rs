@@ -1,17 +1,336 @@
-﻿use core::{
+﻿use crate::{isa::Isa, NUM_HART_MAX};
+use core::{
     fmt::{Display, Formatter, Result},
     ops::Range,
 };
+use rustsbi::HartMask;
 
 /// 从设备树采集的板信息。
 pub(crate) struct BoardInfo {
     pub dtb: Range<usize>,
     pub model: StringInline<128>,
+    /// 可用的硬件线程数量，不含 hartid 超出 [`NUM_HART_MAX`] 的硬件线程。
     pub smp: usize,
+    /// 包含固件的内存。
     pub mem: Range<usize>,
+    /// 所有内存区域。
+    pub memory: MemoryRegions,
     pub uart: Range<usize>,
-    pub test: Range<usize>,
-    pub clint: Range<usize>,
+    /// 串口的中断号。
+    pub uart_irq: Option<usize>,
+    /// SiFive 测试设备。
+    pub test: Option<Range<usize>>,
+    /// `syscon-poweroff` 描述的关机寄存器。
+    pub poweroff: Option<Syscon>,
+    /// `syscon-reboot` 描述的重启寄存器。
+    pub reboot: Option<Syscon>,
+    /// 每个硬件线程的核心本地中断寄存器，以 hartid 为下标。
+    pub harts: [HartLocal; NUM_HART_MAX],
+    /// `/cpus` 描述的每个硬件线程，以 hartid 为下标。
+    pub hart_info: [HartInfo; NUM_HART_MAX],
+    /// `mtime` 寄存器地址。
+    pub mtime: usize,
+    /// M 态 IMSIC。
+    pub imsic_m: Option<Imsic>,
+    /// S 态 IMSIC。
+    pub imsic_s: Option<Imsic>,
+    /// M 态 APLIC 根域，多插槽时每个插槽一个。
+    pub aplic_m: Aplics,
+    /// S 态 APLIC 域，多插槽时每个插槽一个。
+    pub aplic_s: Aplics,
+    /// 第一个插槽的 PLIC。
+    pub plic: Option<Plic>,
+    /// `mtime` 的频率。
+    pub timebase: usize,
+    /// `/cpus/idle-states` 中的挂起状态。
+    pub idle_states: IdleStates,
+    /// hartid 超出 [`NUM_HART_MAX`] 的硬件线程。
+    pub parked_harts: ParkedHarts,
+}
+
+impl BoardInfo {
+    /// 设备树中所有可用硬件线程的 hartid。
+    pub fn hart_ids(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..NUM_HART_MAX).filter(|i| self.harts[*i].present)
+    }
+
+    /// `hart_mask` 选中的可用硬件线程。
+    ///
+    /// `hart_mask` 只覆盖从基址开始的 XLEN 个硬件线程，不必遍历所有硬件线程。
+    pub fn harts_in(&self, hart_mask: HartMask) -> impl Iterator<Item = usize> + '_ {
+        let (_, base) = hart_mask.into_inner();
+        let range = if base == usize::MAX {
+            0..NUM_HART_MAX
+        } else {
+            base.min(NUM_HART_MAX)..base.saturating_add(usize::BITS as _).min(NUM_HART_MAX)
+        };
+        range.filter(move |i| self.harts[*i].present && hart_mask.has_bit(*i))
+    }
+}
+
+/// 一个硬件线程的核心本地中断寄存器地址，0 表示没有这个寄存器。
+///
+/// 多插槽时每个插槽有自己的 CLINT，硬件线程在设备中的序号由设备的 `interrupts-extended` 决定。
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct HartLocal {
+    /// 设备树中有这个硬件线程。
+    pub present: bool,
+    pub msip: usize,
+    pub mtimecmp: usize,
+    pub setssip: usize,
+}
+
+impl HartLocal {
+    /// 结构体大小是 `1 << SIZE_BITS`，方便汇编中以 hartid 索引。
+    pub const SIZE_BITS: usize = 5;
+
+    const ABSENT: Self = Self {
+        present: false,
+        msip: 0,
+        mtimecmp: 0,
+        setssip: 0,
+    };
+}
+
+const _: () = assert!(core::mem::size_of::<HartLocal>() == 1 << HartLocal::SIZE_BITS);
+
+/// `/cpus` 中一个硬件线程的描述。
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct HartInfo {
+    /// `status` 不是 `disabled`，可以启动特权软件。
+    pub enabled: bool,
+    pub isa: Isa,
+    /// `mmu-type`，`None` 表示没有描述或 `riscv,none`。
+    pub mmu: Option<Mmu>,
+}
+
+impl HartInfo {
+    const UNKNOWN: Self = Self {
+        enabled: true,
+        isa: Isa::UNKNOWN,
+        mmu: None,
+    };
+}
+
+impl Display for HartInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}", self.isa)?;
+        if let Some(mmu) = self.mmu {
+            write!(f, ", {mmu}")?;
+        }
+        if !self.enabled {
+            write!(f, ", disabled")?;
+        }
+        Ok(())
+    }
+}
+
+/// 硬件线程支持的分页模式。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Mmu {
+    Sv32,
+    Sv39,
+    Sv48,
+    Sv57,
+}
+
+impl Display for Mmu {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let name = match self {
+            Self::Sv32 => "sv32",
+            Self::Sv39 => "sv39",
+            Self::Sv48 => "sv48",
+            Self::Sv57 => "sv57",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// 一组 IMSIC 中断文件。
+#[derive(Clone)]
+pub(crate) struct Imsic {
+    /// 每组一个寄存器区域，中断文件按 `interrupts-extended` 的顺序依次排列在各区域中。
+    pub regs: MemoryRegions,
+    /// 中断文件的数量，第 `i` 个属于 `i` 号硬件线程。
+    pub harts: usize,
+    /// 每个硬件线程占 `1 << guest_bits` 页。
+    pub guest_bits: usize,
+    /// 多插槽时中断文件分为 `1 << group_bits` 组。
+    pub group_bits: usize,
+    /// 组号在地址中的位置。
+    pub group_shift: usize,
+}
+
+impl Imsic {
+    /// 设备树中没有 `riscv,group-index-shift` 时组号的位置。
+    const DEFAULT_GROUP_SHIFT: usize = 24;
+}
+
+/// 一个 APLIC 中断域。
+pub(crate) struct Aplic {
+    pub range: Range<usize>,
+    pub num_sources: usize,
+    /// 以 MSI 方式投递中断。
+    pub msi: bool,
+}
+
+/// 设备树描述的一组 APLIC 中断域。
+pub(crate) struct Aplics {
+    domains: [Aplic; Self::MAX],
+    len: usize,
+}
+
+impl Aplics {
+    /// 最多记录的中断域数，与 QEMU virt 的插槽数上限相同。
+    const MAX: usize = 8;
+
+    const NONE: Aplic = Aplic {
+        range: 0..0,
+        num_sources: 0,
+        msi: false,
+    };
+    const EMPTY: Self = Self {
+        domains: [Self::NONE; Self::MAX],
+        len: 0,
+    };
+
+    /// 设备树中描述的中断域。
+    pub fn iter(&self) -> impl Iterator<Item = &Aplic> {
+        self.domains[..self.len].iter()
+    }
+
+    fn push(&mut self, aplic: Aplic) {
+        if self.len < Self::MAX {
+            self.domains[self.len] = aplic;
+            self.len += 1;
+        }
+    }
+}
+
+/// 平台级中断控制器。
+pub(crate) struct Plic {
+    pub range: Range<usize>,
+    /// 每个硬件线程 M 态外部中断的上下文号，以 hartid 为下标。
+    pub m_context: [Option<usize>; NUM_HART_MAX],
+}
+
+/// 写入一个值就关机或重启的寄存器。
+#[derive(Clone, Copy)]
+pub(crate) struct Syscon {
+    pub addr: usize,
+    pub value: u32,
+}
+
+/// 设备树描述的挂起状态。
+#[derive(Clone, Copy)]
+pub(crate) struct IdleState {
+    /// `riscv,sbi-suspend-param`。
+    pub suspend_type: Option<u32>,
+    /// `entry-latency-us`。
+    pub entry_us: u32,
+    /// `exit-latency-us`。
+    pub exit_us: u32,
+}
+
+/// hartid 超出 [`NUM_HART_MAX`]、停在固件里的硬件线程。
+pub(crate) struct ParkedHarts {
+    ids: [usize; Self::MAX],
+    /// 停住的硬件线程总数，可能超过记录的数量。
+    count: usize,
+}
+
+impl ParkedHarts {
+    /// 最多记录的 hartid 数。
+    const MAX: usize = 16;
+
+    const EMPTY: Self = Self {
+        ids: [0; Self::MAX],
+        count: 0,
+    };
+
+    /// 记录下来的 hartid。
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.ids[..self.count.min(Self::MAX)].iter().copied()
+    }
+
+    /// 没有记录 hartid 的硬件线程数。
+    pub fn unlisted(&self) -> usize {
+        self.count.saturating_sub(Self::MAX)
+    }
+
+    fn push(&mut self, hartid: usize) {
+        if self.count < Self::MAX {
+            self.ids[self.count] = hartid;
+        }
+        self.count += 1;
+    }
+}
+
+/// 设备树描述的挂起状态表。
+pub(crate) struct IdleStates {
+    states: [IdleState; Self::MAX],
+    len: usize,
+}
+
+impl IdleStates {
+    /// 最多记录的挂起状态数。
+    const MAX: usize = 8;
+
+    const EMPTY: Self = Self {
+        states: [IdleState {
+            suspend_type: None,
+            entry_us: 0,
+            exit_us: 0,
+        }; Self::MAX],
+        len: 0,
+    };
+
+    /// 设备树中描述的挂起状态。
+    pub fn iter(&self) -> impl Iterator<Item = &IdleState> {
+        self.states[..self.len].iter()
+    }
+
+    /// 设备树中没有描述挂起状态。
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// 设备树描述的内存区域表。
+#[derive(Clone)]
+pub(crate) struct MemoryRegions {
+    regions: [Range<usize>; Self::MAX],
+    len: usize,
+}
+
+impl MemoryRegions {
+    /// 最多记录的内存区域数，多出的区域不交给特权软件。
+    const MAX: usize = 8;
+
+    const NONE: Range<usize> = 0..0;
+    const EMPTY: Self = Self {
+        regions: [Self::NONE; Self::MAX],
+        len: 0,
+    };
+
+    /// 设备树中描述的内存区域。
+    pub fn iter(&self) -> impl Iterator<Item = &Range<usize>> {
+        self.regions[..self.len].iter()
+    }
+
+    fn push(&mut self, range: Range<usize>) {
+        if self.len < Self::MAX && !range.is_empty() {
+            self.regions[self.len] = range;
+            self.len += 1;
+        }
+    }
+}
+
+impl Default for MemoryRegions {
+    fn default() -> Self {
+        Self::EMPTY
+    }
 }
 
 /// 在栈上存储有限长度字符串。
@@ -25,84 +344,667 @@ impl<const N: usize> Display for StringInline<N> {
     }
 }
 
+const CPUS: &str = "cpus";
+const CPU: &str = "cpu@";
+const IDLE_STATES: &str = "idle-states";
+const INTC: &str = "interrupt-controller";
+
+/// QEMU virt 的串口基址，设备树头不合法时用它报告错误。
+const QEMU_VIRT_UART: usize = 0x1000_0000;
+
+/// 设备树不能解析，或缺少固件必需的设备。
+pub(crate) enum ParseError {
+    /// 设备树头不合法。
+    Header {
+        addr: usize,
+        error: dtb_walker::HeaderError,
+    },
+    /// 缺少固件必需的设备。
+    Missing {
+        device: Required,
+        /// 已经找到的串口基址，用于报告错误。
+        uart: Option<usize>,
+    },
+}
+
+impl ParseError {
+    /// 用于报告错误的串口基址。
+    ///
+    /// 设备树头不合法时找不到串口，假定串口在 QEMU virt 的位置。
+    pub fn uart(&self) -> Option<usize> {
+        match self {
+            Self::Header { .. } => Some(QEMU_VIRT_UART),
+            Self::Missing { uart, .. } => *uart,
+        }
+    }
+}
+
+/// 固件必需的设备。
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Required {
+    /// 包含固件的内存。
+    Memory,
+    /// 串口。
+    Uart,
+    /// 每个硬件线程的定时器。
+    Timer,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let device = match self {
+            Self::Header { addr, error } => {
+                return write!(f, "invalid device tree header at {addr:#x}: {error:?}");
+            }
+            Self::Missing { device, .. } => device,
+        };
+        match device {
+            Required::Memory => write!(
+                f,
+                "no memory node in device tree contains the firmware at {:#x}",
+                crate::_start as usize,
+            ),
+            Required::Uart => write!(
+                f,
+                "no UART compatible with \"ns16550a\" or \"ns16550\" in device tree",
+            ),
+            Required::Timer => write!(
+                f,
+                "no timer compatible with \"sifive,clint0\", \"riscv,clint0\" or \"riscv,aclint-mtimer\" covers every hart in device tree",
+            ),
+        }
+    }
+}
+
 /// 解析设备树。
-pub(crate) fn parse(opaque: usize) -> BoardInfo {
-    use dtb_walker::{Dtb, DtbObj, HeaderError as E, Property, Str, WalkOperation::*};
-    const CPUS: &str = "cpus";
-    const MEMORY: &str = "memory";
-    const SOC: &str = "soc";
-    const UART: &str = "uart";
-    const SERIAL: &str = "serial";
-    const TEST: &str = "test";
-    const CLINT: &str = "clint";
+///
+/// 设备以 `compatible` 识别，不依赖节点名。设备树头不合法或缺少固件必需的设备时返回错误。
+pub(crate) fn parse(opaque: usize) -> core::result::Result<BoardInfo, ParseError> {
+    use dtb_walker::{DtbObj, Property, Str, WalkOperation::*};
 
     let mut ans = BoardInfo {
         dtb: opaque..opaque,
         model: StringInline(0, [0u8; 128]),
         smp: 0,
         mem: 0..0,
+        memory: MemoryRegions::EMPTY,
         uart: 0..0,
-        test: 0..0,
-        clint: 0..0,
+        uart_irq: None,
+        test: None,
+        poweroff: None,
+        reboot: None,
+        harts: [HartLocal::ABSENT; NUM_HART_MAX],
+        hart_info: [HartInfo::UNKNOWN; NUM_HART_MAX],
+        mtime: 0,
+        imsic_m: None,
+        imsic_s: None,
+        aplic_m: Aplics::EMPTY,
+        aplic_s: Aplics::EMPTY,
+        plic: None,
+        timebase: 0,
+        idle_states: IdleStates::EMPTY,
+        parked_harts: ParkedHarts::EMPTY,
     };
-    let dtb = unsafe {
+    let dtb = open(opaque).map_err(|error| ParseError::Header {
+        addr: opaque,
+        error,
+    })?;
+    ans.dtb.end += dtb.total_size();
+    // 先找到所有硬件线程，设备通过 phandle 引用硬件线程
+    let cpus = parse_cpus(&dtb, &mut ans);
+    let mut syscons = Syscons::default();
+    // 节点的属性都在子节点之前，遇到下一个节点时提交上一个节点
+    let mut device = Device::default();
+    dtb.walk(|ctx, obj| match obj {
+        DtbObj::SubNode { name } => {
+            core::mem::take(&mut device).commit(&mut ans, &mut syscons);
+            if ctx.is_root() && name == Str::from(CPUS) {
+                StepOver
+            } else {
+                StepInto
+            }
+        }
+        DtbObj::Property(Property::Model(model)) if ctx.is_root() => {
+            ans.model.0 = model.as_bytes().len();
+            ans.model.1[..ans.model.0].copy_from_slice(model.as_bytes());
+            StepOver
+        }
+        DtbObj::Property(prop) => {
+            device.parse(prop, &cpus);
+            StepOver
+        }
+    });
+    device.commit(&mut ans, &mut syscons);
+    ans.poweroff = syscons.resolve(syscons.poweroff);
+    ans.reboot = syscons.resolve(syscons.reboot);
+
+    let missing = if ans.uart.is_empty() {
+        Some(Required::Uart)
+    } else if ans.mem.is_empty() {
+        Some(Required::Memory)
+    } else if ans.mtime == 0 || ans.hart_ids().any(|i| ans.harts[i].mtimecmp == 0) {
+        Some(Required::Timer)
+    } else {
+        None
+    };
+    match missing {
+        Some(device) => Err(ParseError::Missing {
+            device,
+            uart: (!ans.uart.is_empty()).then_some(ans.uart.start),
+        }),
+        None => Ok(ans),
+    }
+}
+
+/// 硬件线程本地中断控制器的 phandle 到 hartid 的映射。
+struct Cpus {
+    phandle: [u32; NUM_HART_MAX],
+    hartid: [usize; NUM_HART_MAX],
+    len: usize,
+}
+
+impl Cpus {
+    fn hartid(&self, phandle: u32) -> Option<usize> {
+        self.phandle[..self.len]
+            .iter()
+            .position(|p| *p == phandle)
+            .map(|i| self.hartid[i])
+    }
+}
+
+/// 检查 `opaque` 处的设备树头。
+fn open(opaque: usize) -> core::result::Result<dtb_walker::Dtb<'static>, dtb_walker::HeaderError> {
+    use dtb_walker::{Dtb, HeaderError as E};
+    unsafe {
         Dtb::from_raw_parts_filtered(opaque as _, |e| {
             matches!(e, E::Misaligned(4) | E::LastCompVersion(_))
         })
     }
-    .unwrap();
-    ans.dtb.end += dtb.total_size();
+}
+
+/// `opaque` 处的设备树 `/cpus` 中是否有 hartid 为 `hartid` 的硬件线程。
+///
+/// 只遍历 `/cpus`，可以在全局初始化之前调用。设备树不能解析时返回 `false`。
+pub(crate) fn has_hart(opaque: usize, hartid: usize) -> bool {
+    use dtb_walker::{DtbObj, Property, Str, WalkOperation::*};
+    let Ok(dtb) = open(opaque) else {
+        return false;
+    };
+    let mut found = false;
     dtb.walk(|ctx, obj| match obj {
         DtbObj::SubNode { name } => {
-            let current = ctx.name();
+            if (ctx.is_root() && name == Str::from(CPUS))
+                || (ctx.name() == Str::from(CPUS) && name.starts_with(CPU))
+            {
+                StepInto
+            } else {
+                StepOver
+            }
+        }
+        DtbObj::Property(Property::Reg(mut reg)) if ctx.name().starts_with(CPU) => {
+            if reg.next().map_or(false, |reg| reg.start == hartid) {
+                found = true;
+                Terminate
+            } else {
+                StepOut
+            }
+        }
+        DtbObj::Property(_) => StepOver,
+    });
+    found
+}
+
+/// 找到 `/cpus` 下的所有硬件线程和它们的描述，以及 `mtime` 频率和挂起状态。
+///
+/// hartid 超出 [`NUM_HART_MAX`] 的硬件线程不可用，在 [`locate`](crate::trap_stack::locate) 中停住。
+fn parse_cpus(dtb: &dtb_walker::Dtb, ans: &mut BoardInfo) -> Cpus {
+    use dtb_walker::{DtbObj, Property, Str, WalkOperation::*};
+    let mut cpus = Cpus {
+        phandle: [0; NUM_HART_MAX],
+        hartid: [0; NUM_HART_MAX],
+        len: 0,
+    };
+    let mut current = usize::MAX;
+    // 硬件线程的属性顺序不定，节点结束时才知道 hartid
+    let mut info: Option<HartInfo> = None;
+    // 正在解析 `/cpus/idle-states` 的子节点
+    let mut idle_state = false;
+    dtb.walk(|ctx, obj| match obj {
+        DtbObj::SubNode { name } => {
+            idle_state = false;
+            if let Some(info) = info.take() {
+                commit_hart(ans, current, info);
+            }
             if ctx.is_root() {
-                if name == Str::from(CPUS) || name == Str::from(SOC) || name.starts_with(MEMORY) {
+                if name == Str::from(CPUS) {
                     StepInto
                 } else {
                     StepOver
                 }
-            } else if current == Str::from(SOC) {
-                if name.starts_with(UART)
-                    || name.starts_with(SERIAL)
-                    || name.starts_with(TEST)
-                    || name.starts_with(CLINT)
-                {
+            } else if ctx.name() == Str::from(CPUS) {
+                if name.starts_with(CPU) {
+                    current = usize::MAX;
+                    info = Some(HartInfo::UNKNOWN);
+                    StepInto
+                } else if name == Str::from(IDLE_STATES) {
                     StepInto
                 } else {
                     StepOver
                 }
-            } else {
-                if current == Str::from(CPUS) && name.starts_with("cpu@") {
-                    ans.smp += 1;
+            } else if ctx.name().starts_with(CPU) && name.starts_with(INTC) {
+                StepInto
+            } else if ctx.name() == Str::from(IDLE_STATES) {
+                let states = &mut ans.idle_states;
+                if states.len < IdleStates::MAX {
+                    states.states[states.len] = IdleStates::EMPTY.states[0];
+                    states.len += 1;
+                    idle_state = true;
+                    StepInto
+                } else {
+                    StepOver
                 }
+            } else {
                 StepOver
             }
         }
-        DtbObj::Property(Property::Model(model)) if ctx.is_root() => {
-            ans.model.0 = model.as_bytes().len();
-            ans.model.1[..ans.model.0].copy_from_slice(model.as_bytes());
+        DtbObj::Property(Property::General { name, value }) if ctx.name() == Str::from(CPUS) => {
+            if name == Str::from("timebase-frequency") {
+                ans.timebase = be_u32(value) as _;
+            }
             StepOver
         }
-        DtbObj::Property(Property::Reg(mut reg)) => {
-            let node = ctx.name();
-            if node.starts_with(UART) || node.starts_with(SERIAL) {
-                ans.uart = reg.next().unwrap();
-                StepOut
-            } else if node.starts_with(TEST) {
-                ans.test = reg.next().unwrap();
-                StepOut
-            } else if node.starts_with(CLINT) {
-                ans.clint = reg.next().unwrap();
-                StepOut
-            } else if node.starts_with(MEMORY) {
-                ans.mem = reg.next().unwrap();
-                StepOut
-            } else {
-                StepOver
+        DtbObj::Property(Property::General { name, value }) if idle_state => {
+            let state = &mut ans.idle_states.states[ans.idle_states.len - 1];
+            match name.as_bytes() {
+                b"riscv,sbi-suspend-param" => state.suspend_type = Some(be_u32(value)),
+                b"entry-latency-us" => state.entry_us = be_u32(value),
+                b"exit-latency-us" => state.exit_us = be_u32(value),
+                _ => {}
+            }
+            StepOver
+        }
+        DtbObj::Property(Property::Reg(mut reg)) if ctx.name().starts_with(CPU) => {
+            current = reg.next().map_or(usize::MAX, |reg| reg.start);
+            StepOver
+        }
+        DtbObj::Property(Property::Status(status)) if ctx.name().starts_with(CPU) => {
+            if let Some(info) = info.as_mut() {
+                info.enabled = status != Str::from("disabled");
             }
+            StepOver
+        }
+        DtbObj::Property(Property::General { name, value }) if ctx.name().starts_with(CPU) => {
+            if let Some(info) = info.as_mut() {
+                match name.as_bytes() {
+                    b"riscv,isa" | b"riscv,isa-base" => info.isa.parse_string(value),
+                    b"riscv,isa-extensions" => info.isa.parse_list(value),
+                    b"mmu-type" => {
+                        info.mmu = match value.split(|c| *c == 0).next() {
+                            Some(b"riscv,sv32") => Some(Mmu::Sv32),
+                            Some(b"riscv,sv39") => Some(Mmu::Sv39),
+                            Some(b"riscv,sv48") => Some(Mmu::Sv48),
+                            Some(b"riscv,sv57") => Some(Mmu::Sv57),
+                            _ => None,
+                        }
+                    }
+                    // `/cpus` 中没有时使用硬件线程的 `timebase-frequency`
+                    b"timebase-frequency" if ans.timebase == 0 => {
+                        ans.timebase = be_u32(value) as _;
+                    }
+                    _ => {}
+                }
+            }
+            StepOver
+        }
+        DtbObj::Property(Property::PHandle(phandle)) if ctx.name().starts_with(INTC) => {
+            if current < NUM_HART_MAX {
+                cpus.phandle[cpus.len] = phandle.value();
+                cpus.hartid[cpus.len] = current;
+                cpus.len += 1;
+            }
+            StepOut
         }
         DtbObj::Property(_) => StepOver,
     });
+    if let Some(info) = info {
+        commit_hart(ans, current, info);
+    }
+    cpus
+}
+
+/// 记录硬件线程的描述，hartid 超出 [`NUM_HART_MAX`] 的硬件线程只记录 hartid。
+fn commit_hart(ans: &mut BoardInfo, hartid: usize, info: HartInfo) {
+    if hartid < NUM_HART_MAX {
+        if !ans.harts[hartid].present {
+            ans.harts[hartid].present = true;
+            ans.hart_info[hartid] = info;
+            ans.smp += 1;
+        }
+    } else if hartid != usize::MAX {
+        ans.parked_harts.push(hartid);
+    }
+}
+
+/// 读大端 32 位属性值，长度不足时为 0。
+fn be_u32(value: &[u8]) -> u32 {
+    value
+        .get(..4)
+        .map_or(0, |bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// `interrupts-extended` 指向的硬件线程，按在设备中的序号排列。
+struct Targets {
+    hartid: [usize; NUM_HART_MAX],
+    len: usize,
+    /// 每个硬件线程的 M 态外部中断在 `interrupts-extended` 中的序号，以 hartid 为下标。
+    ///
+    /// PLIC 以这个序号为上下文号。
+    m_ext: [Option<usize>; NUM_HART_MAX],
+}
+
+impl Default for Targets {
+    fn default() -> Self {
+        Self {
+            hartid: [usize::MAX; NUM_HART_MAX],
+            len: 0,
+            m_ext: [None; NUM_HART_MAX],
+        }
+    }
+}
+
+impl Targets {
+    /// 可用的硬件线程和它在设备中的序号。
+    fn iter(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.hartid[..self.len]
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, hartid)| *hartid < NUM_HART_MAX)
+    }
+}
+
+/// 正在解析的设备节点。
+///
+/// 设备的信息分散在多个属性里，在节点结束时提交。
+#[derive(Default)]
+struct Device {
+    /// `status` 不是 `okay`。
+    disabled: bool,
+    memory: bool,
+    uart: bool,
+    test: bool,
+    syscon: bool,
+    poweroff: bool,
+    reboot: bool,
+    clint: bool,
+    imsic: bool,
+    aplic: bool,
+    mswi: bool,
+    mtimer: bool,
+    sswi: bool,
+    plic: bool,
+    reg: Range<usize>,
+    /// 第二个寄存器区域，MTIMER 的 `mtimecmp`。
+    reg2: Option<Range<usize>>,
+    /// 包含固件的寄存器区域，用于选择内存。
+    firmware: Option<Range<usize>>,
+    /// 所有寄存器区域，用于记录内存。
+    regions: MemoryRegions,
+    /// `interrupts` 中的第一个中断号。
+    interrupts: Option<usize>,
+    phandle: Option<u32>,
+    /// `syscon-poweroff` 和 `syscon-reboot` 引用的 `syscon` 节点。
+    regmap: Option<u32>,
+    offset: usize,
+    value: u32,
+    /// `interrupts-extended` 中的中断号，区分 M 态和 S 态。
+    irq: u32,
+    targets: Targets,
+    guest_bits: usize,
+    group_bits: usize,
+    group_shift: Option<usize>,
+    num_sources: usize,
+    /// 有子域的 APLIC 是 M 态根域。
+    root: bool,
+    msi: bool,
+}
+
+impl Device {
+    /// M 态外部中断。
+    const IRQ_M_EXT: u32 = 11;
+    /// S 态外部中断。
+    const IRQ_S_EXT: u32 = 9;
+    /// SiFive CLINT 中 `mtimecmp` 数组的偏移。
+    const CLINT_MTIMECMP_OFFSET: usize = 0x4000;
+    /// SiFive CLINT 中 `mtime` 的偏移。
+    const CLINT_MTIME_OFFSET: usize = 0xbff8;
+    /// ACLINT MTIMER 默认布局中 `mtime` 的偏移。
+    const MTIMER_MTIME_OFFSET: usize = 0x7ff8;
+
+    fn parse(&mut self, prop: dtb_walker::Property, cpus: &Cpus) {
+        use dtb_walker::{Property, Str};
+        match prop {
+            Property::Compatible(compatible) => {
+                for s in compatible {
+                    if s == Str::from("ns16550a") || s == Str::from("ns16550") {
+                        self.uart = true;
+                    } else if s == Str::from("sifive,test1") || s == Str::from("sifive,test0") {
+                        self.test = true;
+                    } else if s == Str::from("syscon") {
+                        self.syscon = true;
+                    } else if s == Str::from("syscon-poweroff") {
+                        self.poweroff = true;
+                    } else if s == Str::from("syscon-reboot") {
+                        self.reboot = true;
+                    } else if s == Str::from("sifive,clint0") || s == Str::from("riscv,clint0") {
+                        self.clint = true;
+                    } else if s == Str::from("riscv,imsics") {
+                        self.imsic = true;
+                    } else if s == Str::from("riscv,aplic") {
+                        self.aplic = true;
+                    } else if s == Str::from("riscv,aclint-mswi") {
+                        self.mswi = true;
+                    } else if s == Str::from("riscv,aclint-mtimer") {
+                        self.mtimer = true;
+                    } else if s == Str::from("riscv,aclint-sswi") {
+                        self.sswi = true;
+                    } else if s == Str::from("riscv,plic0") || s == Str::from("sifive,plic-1.0.0") {
+                        self.plic = true;
+                    }
+                }
+            }
+            Property::Status(status) => {
+                self.disabled = status != Str::from("okay") && status != Str::from("ok");
+            }
+            Property::PHandle(phandle) => self.phandle = Some(phandle.value()),
+            Property::Reg(reg) => {
+                let firmware = crate::_start as usize;
+                for (i, range) in reg.enumerate() {
+                    match i {
+                        0 => self.reg = range.clone(),
+                        1 => self.reg2 = Some(range.clone()),
+                        _ => {}
+                    }
+                    if self.firmware.is_none() && range.contains(&firmware) {
+                        self.firmware = Some(range.clone());
+                    }
+                    self.regions.push(range);
+                }
+            }
+            Property::General { name, value } => match name.as_bytes() {
+                b"device_type" => self.memory = value.starts_with(b"memory\0"),
+                b"interrupts" => self.interrupts = Some(be_u32(value) as _),
+                b"regmap" => self.regmap = Some(be_u32(value)),
+                b"offset" => self.offset = be_u32(value) as _,
+                b"value" => self.value = be_u32(value),
+                b"interrupts-extended" => {
+                    self.irq = value.get(4..).map_or(0, be_u32);
+                    // 每个硬件线程可能有多项，例如 CLINT 的软件中断和定时器中断
+                    let mut last = None;
+                    for (i, pair) in value.chunks_exact(8).enumerate() {
+                        let phandle = be_u32(pair);
+                        if be_u32(&pair[4..]) == Self::IRQ_M_EXT {
+                            if let Some(hartid) = cpus.hartid(phandle) {
+                                self.targets.m_ext[hartid].get_or_insert(i);
+                            }
+                        }
+                        if last == Some(phandle) || self.targets.len == NUM_HART_MAX {
+                            continue;
+                        }
+                        last = Some(phandle);
+                        self.targets.hartid[self.targets.len] =
+                            cpus.hartid(phandle).unwrap_or(usize::MAX);
+                        self.targets.len += 1;
+                    }
+                }
+                b"riscv,guest-index-bits" => self.guest_bits = be_u32(value) as _,
+                b"riscv,group-index-bits" => self.group_bits = be_u32(value) as _,
+                b"riscv,group-index-shift" => self.group_shift = Some(be_u32(value) as _),
+                b"riscv,num-sources" => self.num_sources = be_u32(value) as _,
+                b"riscv,children" => self.root = true,
+                b"msi-parent" => self.msi = true,
+                _ => {}
+            },
+            _ => {}
+        }
+    }
 
-    ans
+    fn commit(self, ans: &mut BoardInfo, syscons: &mut Syscons) {
+        if self.disabled {
+            return;
+        }
+        let base = self.reg.start;
+        if let (true, Some(phandle)) = (self.syscon, self.phandle) {
+            syscons.insert(phandle, base);
+        }
+        if self.memory {
+            // 多个内存区域时使用包含固件的区域
+            if let (true, Some(mem)) = (ans.mem.is_empty(), self.firmware) {
+                ans.mem = mem;
+            }
+            for range in self.regions.iter() {
+                ans.memory.push(range.clone());
+            }
+        } else if self.uart {
+            // 多个串口时使用第一个
+            if ans.uart.is_empty() {
+                ans.uart = self.reg;
+                ans.uart_irq = self.interrupts.filter(|irq| *irq != 0);
+            }
+        } else if self.test {
+            ans.test.get_or_insert(self.reg);
+        } else if self.poweroff || self.reboot {
+            let target = SysconRef {
+                regmap: self.regmap,
+                offset: self.offset,
+                value: self.value,
+            };
+            if self.poweroff {
+                syscons.poweroff = Some(target);
+            } else {
+                syscons.reboot = Some(target);
+            }
+        } else if self.clint {
+            // SiFive CLINT 相当于连在一起的 MSWI 和 MTIMER
+            for (i, hartid) in self.targets.iter() {
+                let hart = &mut ans.harts[hartid];
+                hart.msip = base + i * 4;
+                hart.mtimecmp = base + Self::CLINT_MTIMECMP_OFFSET + i * 8;
+            }
+            ans.mtime = base + Self::CLINT_MTIME_OFFSET;
+        } else if self.imsic {
+            let imsic = Imsic {
+                regs: self.regions,
+                harts: self.targets.len,
+                guest_bits: self.guest_bits,
+                group_bits: self.group_bits,
+                group_shift: self.group_shift.unwrap_or(Imsic::DEFAULT_GROUP_SHIFT),
+            };
+            match self.irq {
+                Self::IRQ_M_EXT => ans.imsic_m = Some(imsic),
+                Self::IRQ_S_EXT => ans.imsic_s = Some(imsic),
+                _ => {}
+            }
+        } else if self.aplic {
+            let aplic = Aplic {
+                range: self.reg,
+                num_sources: self.num_sources,
+                msi: self.msi,
+            };
+            if self.root {
+                ans.aplic_m.push(aplic);
+            } else {
+                ans.aplic_s.push(aplic);
+            }
+        } else if self.mswi {
+            for (i, hartid) in self.targets.iter() {
+                ans.harts[hartid].msip = base + i * 4;
+            }
+        } else if self.mtimer {
+            // 只有一个区域时 mtime 在 mtimecmp 数组之后
+            let (mtime, mtimecmp) = match self.reg2 {
+                Some(mtimecmp) => (base, mtimecmp.start),
+                None => (base + Self::MTIMER_MTIME_OFFSET, base),
+            };
+            for (i, hartid) in self.targets.iter() {
+                ans.harts[hartid].mtimecmp = mtimecmp + i * 8;
+            }
+            ans.mtime = mtime;
+        } else if self.sswi {
+            for (i, hartid) in self.targets.iter() {
+                ans.harts[hartid].setssip = base + i * 4;
+            }
+        } else if self.plic && ans.plic.is_none() {
+            // 串口连在第一个插槽的 PLIC 上
+            ans.plic = Some(Plic {
+                range: self.reg,
+                m_context: self.targets.m_ext,
+            });
+        }
+    }
+}
+
+/// `syscon` 节点，以及引用它们的关机和重启节点。
+///
+/// 引用可能出现在 `syscon` 节点之前，遍历结束后再解析。
+#[derive(Default)]
+struct Syscons {
+    /// `syscon` 节点的 phandle 和寄存器基址。
+    nodes: [(u32, usize); Self::MAX],
+    len: usize,
+    poweroff: Option<SysconRef>,
+    reboot: Option<SysconRef>,
+}
+
+/// 关机或重启节点对 `syscon` 节点的引用。
+#[derive(Clone, Copy)]
+struct SysconRef {
+    regmap: Option<u32>,
+    offset: usize,
+    value: u32,
+}
+
+impl Syscons {
+    /// 最多记录的 `syscon` 节点数。
+    const MAX: usize = 8;
+
+    fn insert(&mut self, phandle: u32, base: usize) {
+        if self.len < Self::MAX {
+            self.nodes[self.len] = (phandle, base);
+            self.len += 1;
+        }
+    }
+
+    /// 找到引用的寄存器。
+    fn resolve(&self, target: Option<SysconRef>) -> Option<Syscon> {
+        let target = target?;
+        let regmap = target.regmap?;
+        self.nodes[..self.len]
+            .iter()
+            .find(|(phandle, _)| *phandle == regmap)
+            .map(|(_, base)| Syscon {
+                addr: base + target.offset,
+                value: target.value,
+            })
+    }
 }
@@ -0,0 +1,114 @@
+//! 修补交给特权软件的设备树。
+//!
+//! 设备树复制到固件区域顶端的缓冲区中再修补，QEMU 放置设备树的内存交还特权软件。
+//!
+//! - 在 `/reserved-memory` 下添加 `no-map` 的固件区域，和特权软件只读的设备树缓冲区；
+//! - 在 `/chosen` 下记录固件的身份。
+//!
+//! 修补设备树字节的逻辑在 *fdt-fixup* 中，可以在宿主机上测试。
+
+use crate::LEN_STACK_PER_HART;
+use core::{
+    ops::Range,
+    ptr::addr_of,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use fdt_fixup::{FixupError, Identity, Reserved};
+use rcore_console::log;
+use spin::Once;
+
+/// 缓冲区中留给修补的空间。
+const LEN_SLACK: usize = 4096;
+/// 缓冲区按页对齐，PMP 才能单独保护它。
+const PAGE: usize = 4096;
+
+/// 设备树缓冲区的起始地址，缓冲区结束于固件区域的末端。
+///
+/// 热重启时从缓冲区重新解析设备树，缓冲区不能移动，必须放在 `.data`。
+#[link_section = ".data"]
+static BUFFER: AtomicUsize = AtomicUsize::new(0);
+
+/// 交给特权软件的设备树。
+static DTB: Once<Range<usize>> = Once::new();
+
+/// 在固件区域顶端为长 `len` 的设备树划出缓冲区。
+///
+/// 缓冲区至少要给当前硬件线程留出栈，放不下时返回空的缓冲区。
+/// 热重启时返回已经划出的缓冲区。
+pub(crate) fn reserve(len: usize) -> Range<usize> {
+    extern "C" {
+        static sstack: u8;
+        static estack: u8;
+    }
+    let (stack, end) = unsafe { (addr_of!(sstack) as usize, addr_of!(estack) as usize) };
+    let mut start = BUFFER.load(Ordering::Relaxed);
+    if start == 0 {
+        start = end
+            .checked_sub(len + LEN_SLACK)
+            .map(|start| start & !(PAGE - 1))
+            .filter(|start| *start >= stack + LEN_STACK_PER_HART)
+            .unwrap_or(end);
+        BUFFER.store(start, Ordering::Relaxed);
+    }
+    start..end
+}
+
+/// 把 `dtb` 处的设备树复制到 `buffer` 中修补，`firmware` 是包含缓冲区的固件区域。
+///
+/// 修补失败时交出原来的设备树。
+pub(crate) fn init(
+    dtb: Range<usize>,
+    buffer: Range<usize>,
+    firmware: Range<usize>,
+) -> &'static Range<usize> {
+    DTB.call_once(|| match fixup(dtb.clone(), buffer, firmware) {
+        Ok(patched) => patched,
+        Err(e) => {
+            log::warn!("failed to fix up device tree: {e}");
+            dtb
+        }
+    })
+}
+
+/// 交给特权软件的设备树。
+#[inline]
+pub(crate) fn get() -> &'static Range<usize> {
+    DTB.wait()
+}
+
+fn fixup(
+    dtb: Range<usize>,
+    buffer: Range<usize>,
+    firmware: Range<usize>,
+) -> Result<Range<usize>, FixupError> {
+    // 热重启时设备树已经在缓冲区里
+    if dtb.start != buffer.start {
+        if dtb.len() > buffer.len() {
+            return Err(FixupError::NoSpace {
+                need: dtb.len(),
+                capacity: buffer.len(),
+            });
+        }
+        unsafe { core::ptr::copy(dtb.start as *const u8, buffer.start as *mut u8, dtb.len()) };
+    }
+    let blob = unsafe { core::slice::from_raw_parts_mut(buffer.start as *mut u8, buffer.len()) };
+    let reserved = [
+        Reserved {
+            name: "rustsbi",
+            base: firmware.start as _,
+            size: (buffer.start - firmware.start) as _,
+            no_map: true,
+        },
+        Reserved {
+            name: "rustsbi-dtb",
+            base: buffer.start as _,
+            size: buffer.len() as _,
+            no_map: false,
+        },
+    ];
+    let identity = Identity {
+        implementation: "RustSBI-QEMU",
+        version: env!("CARGO_PKG_VERSION"),
+    };
+    fdt_fixup::fixup(blob, &reserved, &identity).map(|len| buffer.start..buffer.start + len)
+}
@@ -0,0 +1,164 @@
+//! QEMU 通过 `a2` 传给 `-bios` 固件的 `fw_dynamic_info`。
+//!
+//! 结构定义与 OpenSBI 的 `include/sbi/fw_dynamic.h` 相同。
+
+use crate::{hart_id, reboot, riscv_spec::mstatus, trap_detect::try_read_usize, SUPERVISOR_ENTRY};
+use core::{
+    fmt::{Display, Formatter, Result},
+    mem::size_of,
+    ops::Range,
+};
+use rcore_console::log;
+use spin::Once;
+
+/// `fw_dynamic_info` 的魔数，即 "OSBI"。
+const MAGIC: usize = 0x4942_534f;
+/// 支持的最高版本。版本 2 开始有 `boot_hart`。
+const VERSION_MAX: usize = 2;
+
+/// `next_mode` 的取值。
+mod next_mode {
+    pub const U: usize = 0;
+    pub const S: usize = 1;
+}
+
+/// `options`：不打印启动信息。
+pub(crate) const OPTION_NO_BOOT_PRINTS: usize = 1 << 0;
+
+/// `fw_dynamic_info` 的字段，依次为
+/// `magic`、`version`、`next_addr`、`next_mode`、`options`、`boot_hart`。
+const NUM_FIELDS: usize = 6;
+
+/// 下一阶段的启动信息。
+pub(crate) struct NextStage {
+    /// `fw_dynamic_info` 的地址，不存在时为 0。
+    pub info: usize,
+    /// 下一阶段入口。
+    pub addr: usize,
+    /// 下一阶段特权级，以 `mstatus.MPP` 的形式保存。
+    pub mpp: usize,
+    pub options: usize,
+    /// 执行全局初始化的硬件线程，`None` 表示不指定。
+    pub boot_hart: Option<usize>,
+}
+
+/// 解析 `fw_dynamic_info` 的错误。
+pub(crate) enum DynamicError {
+    /// `a2` 不指向可读的内存。
+    Absent,
+    InvalidMagic(usize),
+    UnsupportedVersion(usize),
+    UnsupportedNextMode(usize),
+}
+
+static NEXT_STAGE: Once<NextStage> = Once::new();
+
+/// 解析 `info` 处的 `fw_dynamic_info`。
+pub(crate) fn read(info: usize) -> core::result::Result<NextStage, DynamicError> {
+    if info == 0 || info & (size_of::<usize>() - 1) != 0 {
+        return Err(DynamicError::Absent);
+    }
+    let mut fields = [0usize; NUM_FIELDS];
+    for (i, field) in fields.iter_mut().enumerate() {
+        *field = try_read_usize(info + i * size_of::<usize>()).ok_or(DynamicError::Absent)?;
+    }
+    let [magic, version, next_addr, mode, options, boot_hart] = fields;
+    if magic != MAGIC {
+        return Err(DynamicError::InvalidMagic(magic));
+    }
+    if version > VERSION_MAX {
+        return Err(DynamicError::UnsupportedVersion(version));
+    }
+    let mpp = match mode {
+        next_mode::S => mstatus::MPP_SUPERVISOR,
+        next_mode::U => mstatus::MPP_USER,
+        // 不会把机器态交给下一阶段
+        _ => return Err(DynamicError::UnsupportedNextMode(mode)),
+    };
+    Ok(NextStage {
+        info,
+        addr: next_addr,
+        mpp,
+        options,
+        boot_hart: if version >= 2 && boot_hart != usize::MAX {
+            Some(boot_hart)
+        } else {
+            None
+        },
+    })
+}
+
+/// 确定下一阶段的启动信息。
+///
+/// 没有 `fw_dynamic_info` 或其中没有入口时，以 S 态从 [`SUPERVISOR_ENTRY`] 启动。
+/// `fw_dynamic_info` 无效或入口不在特权软件内存中时同样处理，并打印警告。
+/// 指定的启动硬件线程没有执行全局初始化时也打印警告。
+pub(crate) fn init(info: usize, memory: &Range<usize>) -> &'static NextStage {
+    NEXT_STAGE.call_once(|| {
+        let fallback = NextStage {
+            info: 0,
+            addr: SUPERVISOR_ENTRY,
+            mpp: mstatus::MPP_SUPERVISOR,
+            options: 0,
+            boot_hart: None,
+        };
+        let next_stage = match read(info) {
+            Ok(next_stage) if next_stage.addr == 0 => NextStage {
+                addr: SUPERVISOR_ENTRY,
+                ..next_stage
+            },
+            Ok(next_stage) => next_stage,
+            Err(DynamicError::Absent) => return fallback,
+            Err(e) => {
+                log::warn!("invalid fw_dynamic_info at {info:#x}: {e}, boot from default entry");
+                return fallback;
+            }
+        };
+        // 指定的启动硬件线程不可用时由其他硬件线程执行了全局初始化，热重启时由发起者执行
+        match next_stage.boot_hart {
+            Some(id) if id != hart_id() && !reboot::is_warm() => log::warn!(
+                "boot hart {id} in fw_dynamic_info is unavailable, hart {} boots instead",
+                hart_id(),
+            ),
+            _ => {}
+        }
+        if !(SUPERVISOR_ENTRY..memory.end).contains(&next_stage.addr) {
+            log::warn!(
+                "next stage address {:#x} is out of supervisor memory {:#x?}, boot from default entry",
+                next_stage.addr,
+                SUPERVISOR_ENTRY..memory.end,
+            );
+            return fallback;
+        }
+        next_stage
+    })
+}
+
+pub(crate) fn get() -> &'static NextStage {
+    NEXT_STAGE.wait()
+}
+
+impl Display for DynamicError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Self::Absent => write!(f, "not readable"),
+            Self::InvalidMagic(magic) => write!(f, "invalid magic {magic:#x}"),
+            Self::UnsupportedVersion(version) => write!(f, "unsupported version {version}"),
+            Self::UnsupportedNextMode(mode) => write!(f, "unsupported next mode {mode}"),
+        }
+    }
+}
+
+impl Display for NextStage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let mode = match self.mpp {
+            mstatus::MPP_USER => "U",
+            _ => "S",
+        };
+        write!(f, "{:#x} ({mode}-mode", self.addr)?;
+        if self.info == 0 {
+            write!(f, ", fw_dynamic_info absent")?;
+        }
+        write!(f, ")")
+    }
+}
@@ -0,0 +1,157 @@
+//! 特权软件执行环境配置。
+//!
+//! 按设备树描述的指令集设置 `menvcfg`、`mseccfg` 和 `mstateen0`，打开特权软件可以使用的扩展。
+//! 这些寄存器的字段都是 WARL，写入后读回的才是实际生效的配置。
+//! 设备树没有描述指令集时尝试打开所有字段。
+
+use crate::{
+    hart_csr_utils::Fields,
+    hart_id,
+    isa::{Ext, Isa},
+    BOARD_INFO,
+};
+use rcore_console::log;
+
+pub(crate) const CSR_MENVCFG: usize = 0x30a;
+pub(crate) const CSR_MSECCFG: usize = 0x747;
+const CSR_MSTATEEN0: usize = 0x30c;
+
+/// `menvcfg` 字段。
+pub(crate) mod menvcfg {
+    /// 缓存块失效指令执行失效操作。
+    pub const CBIE_INVALIDATE: usize = 0b11 << 4;
+    pub const CBCFE: usize = 1 << 6;
+    pub const CBZE: usize = 1 << 7;
+    pub const ADUE: usize = 1 << 61;
+    pub const PBMTE: usize = 1 << 62;
+    pub const STCE: usize = 1 << 63;
+}
+
+/// `mseccfg` 字段。
+pub(crate) mod mseccfg {
+    /// Smepmp 机器模式锁定，由 [`crate::pmp`] 设置。
+    pub const MML: usize = 1 << 0;
+    /// Smepmp 机器模式白名单。
+    pub const MMWP: usize = 1 << 1;
+    pub const RLB: usize = 1 << 2;
+    pub const USEED: usize = 1 << 8;
+    pub const SSEED: usize = 1 << 9;
+}
+
+/// `mstateen0` 字段。
+mod mstateen0 {
+    pub const FCSR: usize = 1 << 1;
+    pub const CONTEXT: usize = 1 << 57;
+    pub const IMSIC: usize = 1 << 58;
+    pub const AIA: usize = 1 << 59;
+    pub const CSRIND: usize = 1 << 60;
+    pub const ENVCFG: usize = 1 << 62;
+    pub const SE0: usize = 1 << 63;
+}
+
+/// 写 CSR 并读回，CSR 不存在时返回 `None`。
+macro_rules! try_set {
+    ($csr:expr, $bits:expr) => {{
+        let bits = $bits;
+        if try_write_csr!($csr, bits) {
+            try_read_csr!($csr)
+        } else {
+            None
+        }
+    }};
+}
+
+/// 设置当前硬件线程的执行环境配置并打印结果。
+pub(crate) fn init_hart() {
+    let isa = BOARD_INFO.wait().hart_info[hart_id()].isa;
+    let menvcfg = try_set!(CSR_MENVCFG, menvcfg_bits(&isa));
+    // 保留 PMP 相关的字段
+    let mseccfg = try_read_csr!(CSR_MSECCFG).and_then(|bits| {
+        let bits = bits & !(mseccfg::SSEED | mseccfg::USEED) | mseccfg_bits(&isa);
+        try_set!(CSR_MSECCFG, bits)
+    });
+    let mstateen0 = try_set!(CSR_MSTATEEN0, mstateen0_bits(&isa));
+    log::info!(
+        "hart {} envcfg: menvcfg = {}, mseccfg = {}, mstateen0 = {}",
+        hart_id(),
+        Fields(menvcfg, &MENVCFG_NAMES),
+        Fields(mseccfg, &MSECCFG_NAMES),
+        Fields(mstateen0, &MSTATEEN0_NAMES),
+    );
+}
+
+fn menvcfg_bits(isa: &Isa) -> usize {
+    use menvcfg::*;
+    let mut bits = 0;
+    if isa.may_have(Ext::Zicbom) {
+        bits |= CBIE_INVALIDATE | CBCFE;
+    }
+    if isa.may_have(Ext::Zicboz) {
+        bits |= CBZE;
+    }
+    if isa.may_have(Ext::Svadu) {
+        bits |= ADUE;
+    }
+    if isa.may_have(Ext::Svpbmt) {
+        bits |= PBMTE;
+    }
+    if isa.may_have(Ext::Sstc) {
+        bits |= STCE;
+    }
+    bits
+}
+
+fn mseccfg_bits(isa: &Isa) -> usize {
+    use mseccfg::*;
+    if isa.may_have(Ext::Zkr) {
+        SSEED | USEED
+    } else {
+        0
+    }
+}
+
+fn mstateen0_bits(isa: &Isa) -> usize {
+    use mstateen0::*;
+    // `sstateen0` 和 `senvcfg` 总是交给特权软件
+    let mut bits = SE0 | ENVCFG;
+    if isa.may_have(Ext::Zfinx) {
+        bits |= FCSR;
+    }
+    if isa.may_have(Ext::Sdtrig) {
+        bits |= CONTEXT;
+    }
+    if isa.may_have(Ext::Ssaia) {
+        bits |= AIA | IMSIC;
+    }
+    if isa.may_have(Ext::Sscsrind) || isa.may_have(Ext::Ssaia) {
+        bits |= CSRIND;
+    }
+    bits
+}
+
+pub(crate) const MENVCFG_NAMES: [(usize, &str); 6] = [
+    (menvcfg::CBIE_INVALIDATE, "cbie"),
+    (menvcfg::CBCFE, "cbcfe"),
+    (menvcfg::CBZE, "cbze"),
+    (menvcfg::ADUE, "adue"),
+    (menvcfg::PBMTE, "pbmte"),
+    (menvcfg::STCE, "stce"),
+];
+
+const MSECCFG_NAMES: [(usize, &str); 5] = [
+    (mseccfg::MML, "mml"),
+    (mseccfg::MMWP, "mmwp"),
+    (mseccfg::RLB, "rlb"),
+    (mseccfg::USEED, "useed"),
+    (mseccfg::SSEED, "sseed"),
+];
+
+const MSTATEEN0_NAMES: [(usize, &str); 7] = [
+    (mstateen0::FCSR, "fcsr"),
+    (mstateen0::CONTEXT, "context"),
+    (mstateen0::IMSIC, "imsic"),
+    (mstateen0::AIA, "aia"),
+    (mstateen0::CSRIND, "csrind"),
+    (mstateen0::ENVCFG, "envcfg"),
+    (mstateen0::SE0, "se0"),
+];
@@ -1,188 +1,223 @@
-pub(crate) fn print_pmps() {
-    const ITEM_PER_CFG: usize = core::mem::size_of::<usize>();
-    const CFG_STEP: usize = ITEM_PER_CFG / core::mem::size_of::<u32>();
-
-    let mut i_cfg = 0;
-    while i_cfg < 4 {
-        let base = i_cfg * core::mem::size_of::<u32>();
-        let mut cfg = pmpcfg(i_cfg);
-        for i_addr in 0..ITEM_PER_CFG {
-            match (cfg >> 3) & 0b11 {
-                0b00 => {}
-                0b01 => dump_pmp(
-                    base + i_addr,
-                    pmpaddr(base + i_addr - 1) << 2,
-                    pmpaddr(base + i_addr) << 2,
-                    cfg,
-                ),
-                0b10 => {
-                    let s = pmpaddr(base + i_addr);
-                    dump_pmp(base + i_addr, s << 2, (s + 1) << 2, cfg);
-                }
-                0b11 => {
-                    let addr = pmpaddr(base + i_addr);
-                    let len = 1usize << (addr.trailing_ones() + 2);
-                    let s = (addr & !(len - 1)) << 2;
-                    let e = s + len;
-                    dump_pmp(base + i_addr, s, e, cfg);
-                }
-                _ => unreachable!(),
-            };
-            cfg >>= 8;
+//! 硬件线程 CSR 报告。
+//!
+//! 每个硬件线程初始化完成后以 `HART_REPORT` 指定的日志级别打印报告，默认 `info`，`off` 关闭。
+//! 特权软件可以通过 RustSBI 固件扩展读到当前硬件线程的报告。
+
+use crate::{
+    envcfg, hart_id,
+    pmp::{self, pmp_range, pmpcfg, NUM_PMP, PMP_L, PMP_R, PMP_W, PMP_X},
+    BOARD_INFO, SUPERVISOR_ENTRY,
+};
+use core::fmt::{self, Display, Formatter, Write};
+use rcore_console::log;
+use rustsbi::SbiRet;
+
+/// RustSBI 固件扩展，低位是 RustSBI 的实现编号。
+pub(crate) const EID_RUSTSBI: usize = 0x0a00_0004;
+/// 把当前硬件线程的报告写入特权软件的缓冲区。
+///
+/// `a0` 是缓冲区长度，`a1` 和 `a2` 是缓冲区物理地址的低位和高位。
+/// 返回报告的完整长度，大于缓冲区长度时报告被截断。
+const READ_HART_REPORT: usize = 0;
+
+const CSR_SCOUNTEREN: usize = 0x106;
+const CSR_MISA: usize = 0x301;
+const CSR_MEDELEG: usize = 0x302;
+const CSR_MIDELEG: usize = 0x303;
+const CSR_MCOUNTEREN: usize = 0x306;
+const CSR_MCOUNTINHIBIT: usize = 0x320;
+
+/// 以 `HART_REPORT` 指定的日志级别打印当前硬件线程的报告。
+pub(crate) fn report() {
+    let level = option_env!("HART_REPORT").map_or(Some(log::Level::Info), |s| {
+        s.parse::<log::LevelFilter>()
+            .map_or(Some(log::Level::Info), |filter| filter.to_level())
+    });
+    if let Some(level) = level {
+        log::log!(level, "{HartReport}");
+    }
+}
+
+/// 处理 RustSBI 固件扩展。
+pub(crate) fn handle(fid: usize, [a0, a1, a2]: [usize; 3]) -> SbiRet {
+    match fid {
+        READ_HART_REPORT => read_report(a0, a1, a2),
+        _ => SbiRet::not_supported(),
+    }
+}
+
+/// 把报告写入特权软件内存中的缓冲区。
+fn read_report(num_bytes: usize, base_lo: usize, base_hi: usize) -> SbiRet {
+    let mem = SUPERVISOR_ENTRY..BOARD_INFO.wait().mem.end;
+    let valid = base_hi == 0
+        && (num_bytes == 0
+            || base_lo
+                .checked_add(num_bytes - 1)
+                .map_or(false, |last| mem.contains(&base_lo) && mem.contains(&last)));
+    if !valid {
+        return SbiRet::invalid_param();
+    }
+    let _window = pmp::map_supervisor(base_lo..base_lo + num_bytes);
+    // 长度为 0 时只返回报告的长度，`base_lo` 没有检查，不能用来构造切片
+    let buf: &mut [u8] = if num_bytes == 0 {
+        &mut []
+    } else {
+        unsafe { core::slice::from_raw_parts_mut(base_lo as *mut u8, num_bytes) }
+    };
+    let mut writer = Truncate { buf, len: 0 };
+    write!(writer, "{HartReport}").unwrap();
+    SbiRet::success(writer.len)
+}
+
+/// 写入缓冲区，写满后丢弃，只记录完整长度。
+struct Truncate<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Truncate<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Some(free) = self.buf.get_mut(self.len..) {
+            let n = free.len().min(s.len());
+            free[..n].copy_from_slice(&s.as_bytes()[..n]);
         }
-        i_cfg += CFG_STEP;
+        self.len += s.len();
+        Ok(())
     }
 }
 
-#[inline]
-fn dump_pmp(i: usize, s: usize, e: usize, cfg: usize) {
-    println!(
-        "[rustsbi] pmp{i:02}: {s:#010x}..{e:#010x} ({}{}{})",
-        if cfg & 0b100 != 0 { "x" } else { "-" },
-        if cfg & 0b010 != 0 { "w" } else { "-" },
-        if cfg & 0b001 != 0 { "r" } else { "-" },
-    );
+/// 当前硬件线程的 CSR 报告，格式化时读取 CSR。
+struct HartReport;
+
+impl Display for HartReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let id = hart_id();
+        write!(f, "hart {id} misa: {}", Misa(try_read_csr!(CSR_MISA)))?;
+        write!(
+            f,
+            "\nhart {id} mideleg: {}",
+            Fields(try_read_csr!(CSR_MIDELEG), &MIDELEG_NAMES),
+        )?;
+        write!(
+            f,
+            "\nhart {id} medeleg: {}",
+            Fields(try_read_csr!(CSR_MEDELEG), &MEDELEG_NAMES),
+        )?;
+        write!(
+            f,
+            "\nhart {id} counters: mcounteren = {}, scounteren = {}, mcountinhibit = {}",
+            Fields(try_read_csr!(CSR_MCOUNTEREN), &[]),
+            Fields(try_read_csr!(CSR_SCOUNTEREN), &[]),
+            Fields(try_read_csr!(CSR_MCOUNTINHIBIT), &[]),
+        )?;
+        write!(
+            f,
+            "\nhart {id} menvcfg: {}",
+            Fields(try_read_csr!(envcfg::CSR_MENVCFG), &envcfg::MENVCFG_NAMES),
+        )?;
+        let mut active = 0;
+        for i in 0..NUM_PMP {
+            let Some(cfg) = pmpcfg(i) else {
+                break;
+            };
+            if let Some(range) = pmp_range(i, cfg) {
+                write!(
+                    f,
+                    "\nhart {id} pmp{i:02}: {:#010x}..{:#010x} ({}{}{}{})",
+                    range.start,
+                    range.end,
+                    if cfg & PMP_L != 0 { "l" } else { "-" },
+                    if cfg & PMP_X != 0 { "x" } else { "-" },
+                    if cfg & PMP_W != 0 { "w" } else { "-" },
+                    if cfg & PMP_R != 0 { "r" } else { "-" },
+                )?;
+                active += 1;
+            }
+        }
+        if active == 0 {
+            write!(f, "\nhart {id} pmp: no active entry")?;
+        }
+        Ok(())
+    }
 }
 
-fn pmpcfg(i: usize) -> usize {
-    use riscv::register::*;
-    match i {
-        0 => pmpcfg0::read().bits,
-        #[cfg(target_arch = "riscv32")]
-        1 => pmpcfg1::read().bits,
-        2 => pmpcfg2::read().bits,
-        #[cfg(target_arch = "riscv32")]
-        3 => pmpcfg3::read().bits,
-        _ => todo!(),
+/// 按字段名打印 CSR，CSR 不存在时打印 `absent`。
+pub(crate) struct Fields<'a>(pub Option<usize>, pub &'a [(usize, &'a str)]);
+
+impl Display for Fields<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let Some(bits) = self.0 else {
+            return write!(f, "absent");
+        };
+        write!(f, "{bits:#x}")?;
+        if self.1.is_empty() {
+            return Ok(());
+        }
+        write!(f, " [")?;
+        let mut first = true;
+        for (mask, name) in self.1 {
+            if bits & mask == *mask {
+                if !first {
+                    write!(f, " ")?;
+                }
+                write!(f, "{name}")?;
+                first = false;
+            }
+        }
+        write!(f, "]")
     }
 }
 
-fn pmpaddr(i: usize) -> usize {
-    use riscv::register::*;
-    match i {
-        0x0 => pmpaddr0::read(),
-        0x1 => pmpaddr1::read(),
-        0x2 => pmpaddr2::read(),
-        0x3 => pmpaddr3::read(),
-        0x4 => pmpaddr4::read(),
-        0x5 => pmpaddr5::read(),
-        0x6 => pmpaddr6::read(),
-        0x7 => pmpaddr7::read(),
-        0x8 => pmpaddr8::read(),
-        0x9 => pmpaddr9::read(),
-        0xa => pmpaddr10::read(),
-        0xb => pmpaddr11::read(),
-        0xc => pmpaddr12::read(),
-        0xd => pmpaddr13::read(),
-        0xe => pmpaddr14::read(),
-        0xf => pmpaddr15::read(),
-        _ => todo!(),
+/// 按 `MXL` 和扩展字母打印 `misa`。
+struct Misa(Option<usize>);
+
+impl Display for Misa {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let Some(bits) = self.0.filter(|bits| *bits != 0) else {
+            return write!(f, "not implemented");
+        };
+        match bits >> (usize::BITS - 2) {
+            1 => write!(f, "rv32")?,
+            2 => write!(f, "rv64")?,
+            _ => write!(f, "rv128")?,
+        }
+        for (i, c) in ('a'..='z').enumerate() {
+            if bits & (1 << i) != 0 {
+                write!(f, "{c}")?;
+            }
+        }
+        write!(f, " ({bits:#x})")
     }
 }
 
-// pub(crate) fn print_hart_csrs() {
-//     print_misa();
-//     print_mideleg();
-//     print_medeleg();
-// }
-
-// #[inline]
-// fn print_misa() {
-//     let isa = misa::read();
-//     if let Some(isa) = isa {
-//         let mxl_str = match isa.mxl() {
-//             MXL::XLEN32 => "RV32",
-//             MXL::XLEN64 => "RV64",
-//             MXL::XLEN128 => "RV128",
-//         };
-//         print!("[rustsbi] misa: {mxl_str}");
-//         for ext in 'A'..='Z' {
-//             if isa.has_extension(ext) {
-//                 print!("{ext}");
-//             }
-//         }
-//         println!();
-//     }
-// }
-
-// #[inline]
-// fn print_mideleg() {
-//     let mideleg = mideleg::read();
-//     let mut delegs = Vec::new();
-//     if mideleg.usoft() {
-//         delegs.push("usoft")
-//     }
-//     if mideleg.utimer() {
-//         delegs.push("utimer")
-//     }
-//     if mideleg.uext() {
-//         delegs.push("uext")
-//     }
-//     if mideleg.ssoft() {
-//         delegs.push("ssoft")
-//     }
-//     if mideleg.stimer() {
-//         delegs.push("stimer")
-//     }
-//     if mideleg.sext() {
-//         delegs.push("sext")
-//     }
-//     println!(
-//         "[rustsbi] mideleg: {} ({:#x})",
-//         delegs.join(", "),
-//         mideleg.bits()
-//     );
-// }
-
-// #[inline]
-// fn print_medeleg() {
-//     let medeleg = medeleg::read();
-//     let mut delegs = Vec::new();
-//     if medeleg.instruction_misaligned() {
-//         delegs.push("ima")
-//     }
-//     if medeleg.instruction_fault() {
-//         delegs.push("ia") // instruction access
-//     }
-//     if medeleg.illegal_instruction() {
-//         delegs.push("illinsn")
-//     }
-//     if medeleg.breakpoint() {
-//         delegs.push("bkpt")
-//     }
-//     if medeleg.load_misaligned() {
-//         delegs.push("lma")
-//     }
-//     if medeleg.load_fault() {
-//         delegs.push("la") // load access
-//     }
-//     if medeleg.store_misaligned() {
-//         delegs.push("sma")
-//     }
-//     if medeleg.store_fault() {
-//         delegs.push("sa") // store access
-//     }
-//     if medeleg.user_env_call() {
-//         delegs.push("uecall")
-//     }
-//     if medeleg.supervisor_env_call() {
-//         delegs.push("secall")
-//     }
-//     if medeleg.machine_env_call() {
-//         delegs.push("mecall")
-//     }
-//     if medeleg.instruction_page_fault() {
-//         delegs.push("ipage")
-//     }
-//     if medeleg.load_page_fault() {
-//         delegs.push("lpage")
-//     }
-//     if medeleg.store_page_fault() {
-//         delegs.push("spage")
-//     }
-//     println!(
-//         "[rustsbi] medeleg: {} ({:#x})",
-//         delegs.join(", "),
-//         medeleg.bits()
-//     );
-// }
+const MIDELEG_NAMES: [(usize, &str); 8] = [
+    (1 << 1, "ssoft"),
+    (1 << 2, "vssoft"),
+    (1 << 5, "stimer"),
+    (1 << 6, "vstimer"),
+    (1 << 9, "sext"),
+    (1 << 10, "vsext"),
+    (1 << 12, "sgext"),
+    (1 << 13, "lcofi"),
+];
+
+const MEDELEG_NAMES: [(usize, &str); 18] = [
+    (1 << 0, "ima"),
+    (1 << 1, "iaf"),
+    (1 << 2, "illinsn"),
+    (1 << 3, "bkpt"),
+    (1 << 4, "lma"),
+    (1 << 5, "laf"),
+    (1 << 6, "sma"),
+    (1 << 7, "saf"),
+    (1 << 8, "uecall"),
+    (1 << 9, "secall"),
+    (1 << 10, "vsecall"),
+    (1 << 11, "mecall"),
+    (1 << 12, "ipage"),
+    (1 << 13, "lpage"),
+    (1 << 15, "spage"),
+    (1 << 20, "igpage"),
+    (1 << 21, "lgpage"),
+    (1 << 23, "sgpage"),
+];
@@ -0,0 +1,94 @@
+//! 模拟特权软件执行的非法指令。
+//!
+//! 目前只模拟读计数器 CSR 的指令：`time` 读自 CLINT 的 `mtime`，
+//! `cycle`、`instret` 和 `hpmcounter3`~`hpmcounter31` 读自对应的 M 态计数器。
+//! 不能模拟的非法指令转交给特权软件。
+
+use crate::{clint, misaligned::Previous, pmu, riscv_spec::mepc, trap_redirect};
+use fast_trap::{EntireContext, EntireResult};
+use misaligned_emu::Hart;
+use riscv::register::{mcause, mtval, scounteren};
+use sbi_spec::pmu::firmware_event;
+
+const CSR_CYCLE: usize = 0xc00;
+const CSR_TIME: usize = 0xc01;
+const CSR_INSTRET: usize = 0xc02;
+const CSR_HPMCOUNTER31: usize = 0xc1f;
+
+/// 模拟非法指令的完整路径。
+pub(crate) extern "C" fn handle(ctx: EntireContext) -> EntireResult {
+    let (mut ctx, _) = ctx.split();
+    let cause = mcause::read().bits();
+    let tval = mtval::read();
+    let pc = mepc::read();
+    pmu::record(firmware_event::ILLEGAL_INSN);
+    let mut hart = Previous::new(ctx.regs());
+    // 硬件可能不在 mtval 中提供指令编码
+    let insn = if tval != 0 {
+        Ok(tval as u32)
+    } else {
+        misaligned_emu::fetch(&mut hart, pc)
+    };
+    // 取指令引发的陷入会修改 mepc
+    mepc::write(pc);
+    match insn {
+        Ok(insn) => match decode(insn).and_then(|csr| emulate(&mut hart, csr)) {
+            Some(()) => mepc::write(pc + 4),
+            None => trap_redirect::redirect_exception(cause, tval),
+        },
+        Err(fault) => trap_redirect::redirect_exception(fault.cause, fault.tval),
+    }
+    ctx.restore()
+}
+
+/// 只读不写的 CSR 指令。
+struct CsrRead {
+    csr: usize,
+    rd: u8,
+}
+
+/// 解码 CSR 指令。
+///
+/// 写 CSR 的指令不可能由固件模拟，返回 `None`。
+fn decode(insn: u32) -> Option<CsrRead> {
+    const OPCODE_SYSTEM: u32 = 0b111_0011;
+    if insn & 0x7f != OPCODE_SYSTEM {
+        return None;
+    }
+    let rs1 = (insn >> 15) & 0x1f;
+    match (insn >> 12) & 0b111 {
+        // csrrs/csrrc/csrrsi/csrrci，rs1 或 uimm 为 0 时不写 CSR
+        0b010 | 0b011 | 0b110 | 0b111 if rs1 == 0 => Some(CsrRead {
+            csr: (insn >> 20) as usize,
+            rd: ((insn >> 7) & 0x1f) as u8,
+        }),
+        _ => None,
+    }
+}
+
+/// 模拟读 CSR。
+fn emulate(hart: &mut Previous, insn: CsrRead) -> Option<()> {
+    if !(CSR_CYCLE..=CSR_HPMCOUNTER31).contains(&insn.csr) {
+        return None;
+    }
+    let offset = insn.csr - CSR_CYCLE;
+    // 来自 U 态的访问还受 scounteren 控制
+    if !trap_redirect::from_supervisor() {
+        let scounteren = scounteren::read();
+        let enabled = match insn.csr {
+            CSR_CYCLE => scounteren.cy(),
+            CSR_TIME => scounteren.tm(),
+            CSR_INSTRET => scounteren.ir(),
+            _ => scounteren.hpm(offset),
+        };
+        if !enabled {
+            return None;
+        }
+    }
+    let val = match insn.csr {
+        CSR_TIME => clint::mtime() as usize,
+        _ => pmu::read_hw_counter(offset)?,
+    };
+    hart.set_x(insn.rd, val);
+    Some(())
+}
@@ -0,0 +1,209 @@
+//! 指令集描述。
+//!
+//! 从设备树的 `riscv,isa`、`riscv,isa-base` 和 `riscv,isa-extensions` 中收集，只记录固件关心的多字母扩展。
+
+use core::fmt::{Display, Formatter, Result};
+
+/// 固件关心的多字母扩展。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Ext {
+    Zicsr,
+    Zifencei,
+    Zicntr,
+    Zihpm,
+    Zicbom,
+    Zicboz,
+    Zkr,
+    Zfinx,
+    Svpbmt,
+    Svnapot,
+    Svinval,
+    Svadu,
+    Sstc,
+    Sscofpmf,
+    Ssaia,
+    Smaia,
+    Smstateen,
+    Sscsrind,
+    Smcsrind,
+    Smepmp,
+    Sdtrig,
+}
+
+impl Ext {
+    /// 扩展和它在指令集字符串中的名字，按字符串中的顺序排列。
+    const ALL: [(Self, &'static str); 21] = [
+        (Self::Zicsr, "zicsr"),
+        (Self::Zifencei, "zifencei"),
+        (Self::Zicntr, "zicntr"),
+        (Self::Zihpm, "zihpm"),
+        (Self::Zicbom, "zicbom"),
+        (Self::Zicboz, "zicboz"),
+        (Self::Zkr, "zkr"),
+        (Self::Zfinx, "zfinx"),
+        (Self::Sdtrig, "sdtrig"),
+        (Self::Smaia, "smaia"),
+        (Self::Smcsrind, "smcsrind"),
+        (Self::Smepmp, "smepmp"),
+        (Self::Smstateen, "smstateen"),
+        (Self::Ssaia, "ssaia"),
+        (Self::Sscofpmf, "sscofpmf"),
+        (Self::Sscsrind, "sscsrind"),
+        (Self::Sstc, "sstc"),
+        (Self::Svadu, "svadu"),
+        (Self::Svinval, "svinval"),
+        (Self::Svnapot, "svnapot"),
+        (Self::Svpbmt, "svpbmt"),
+    ];
+
+    #[inline]
+    const fn bit(self) -> u64 {
+        1 << self as u32
+    }
+}
+
+/// 一个硬件线程的指令集。
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Isa {
+    /// `XLEN`，0 表示设备树没有描述。
+    xlen: u8,
+    /// 单字母扩展，第 `i` 位表示字母 `'a' + i`。
+    letters: u32,
+    /// 多字母扩展，以 [`Ext`] 为位序号。
+    exts: u64,
+}
+
+impl Isa {
+    pub const UNKNOWN: Self = Self {
+        xlen: 0,
+        letters: 0,
+        exts: 0,
+    };
+
+    /// 设备树是否描述了指令集。
+    #[inline]
+    pub fn is_known(&self) -> bool {
+        self.letters != 0
+    }
+
+    /// 是否有多字母扩展 `ext`。
+    #[inline]
+    pub fn has(&self, ext: Ext) -> bool {
+        self.exts & ext.bit() != 0
+    }
+
+    /// 设备树没有描述指令集，或描述了 `ext`。
+    ///
+    /// 依赖扩展的设置仍要探测 CSR 是否存在，设备树只用来排除明确没有的扩展。
+    #[inline]
+    pub fn may_have(&self, ext: Ext) -> bool {
+        !self.is_known() || self.has(ext)
+    }
+
+    /// 解析 `riscv,isa` 或 `riscv,isa-base`，例如 `rv64imafdch_zicbom_sstc`。
+    pub fn parse_string(&mut self, value: &[u8]) {
+        let value = value.split(|c| *c == 0).next().unwrap_or(&[]);
+        let Some(rest) = value
+            .get(..2)
+            .filter(|rv| rv.eq_ignore_ascii_case(b"rv"))
+            .map(|_| &value[2..])
+        else {
+            return;
+        };
+        let digits = rest.iter().take_while(|c| c.is_ascii_digit()).count();
+        self.xlen = match &rest[..digits] {
+            b"32" => 32,
+            b"64" => 64,
+            b"128" => 128,
+            _ => return,
+        };
+        let mut tokens = rest[digits..].split(|c| *c == b'_');
+        // 第一段是单字母扩展，可能紧跟一个多字母扩展
+        if let Some(first) = tokens.next() {
+            let mut last_digit = false;
+            for (i, c) in first.iter().map(u8::to_ascii_lowercase).enumerate() {
+                match c {
+                    b's' | b'z' | b'x' => {
+                        self.insert(&first[i..]);
+                        break;
+                    }
+                    // 版本号
+                    b'0'..=b'9' => last_digit = true,
+                    b'p' if last_digit => {}
+                    b'a'..=b'z' => {
+                        last_digit = false;
+                        self.insert_letter(c);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        for token in tokens {
+            self.insert(token);
+        }
+    }
+
+    /// 解析 `riscv,isa-extensions`，以 `\0` 分隔的扩展名。
+    pub fn parse_list(&mut self, value: &[u8]) {
+        for name in value.split(|c| *c == 0) {
+            self.insert(name);
+        }
+    }
+
+    fn insert(&mut self, name: &[u8]) {
+        // 去掉版本号，固件关心的扩展名中没有数字
+        let len = name
+            .iter()
+            .skip(1)
+            .position(u8::is_ascii_digit)
+            .map_or(name.len(), |i| i + 1);
+        let name = &name[..len];
+        match name {
+            [] => {}
+            [c] => self.insert_letter(c.to_ascii_lowercase()),
+            _ => {
+                if let Some((ext, _)) = Ext::ALL
+                    .iter()
+                    .find(|(_, s)| s.as_bytes().eq_ignore_ascii_case(name))
+                {
+                    self.exts |= ext.bit();
+                }
+            }
+        }
+    }
+
+    fn insert_letter(&mut self, c: u8) {
+        if c == b'g' {
+            for c in *b"imafd" {
+                self.insert_letter(c);
+            }
+            self.exts |= Ext::Zicsr.bit() | Ext::Zifencei.bit();
+        } else if c.is_ascii_lowercase() {
+            self.letters |= 1 << (c - b'a');
+        }
+    }
+}
+
+impl Display for Isa {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        if !self.is_known() {
+            return write!(f, "unknown ISA");
+        }
+        // `riscv,isa-extensions` 不含 `XLEN`
+        match self.xlen {
+            0 => write!(f, "rv{}", usize::BITS)?,
+            xlen => write!(f, "rv{xlen}")?,
+        }
+        for c in b'a'..=b'z' {
+            if self.letters & (1 << (c - b'a')) != 0 {
+                write!(f, "{}", c as char)?;
+            }
+        }
+        for (ext, name) in Ext::ALL {
+            if self.has(ext) {
+                write!(f, "_{name}")?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+//! SBI v0.1 遗留扩展。
+//!
+//! 遗留调用只在 `a0` 返回一个值，不修改其他寄存器。
+//! 核间中断和远程屏障的硬件线程掩码是特权软件地址空间中的指针，通过 `mstatus.MPRV` 读取。
+
+use crate::{
+    clint, console,
+    misaligned::{self, Fault},
+    qemu_test, rfence,
+    riscv_spec::mepc,
+    trap_redirect, uart16550,
+};
+use riscv::register::mip;
+use rustsbi::{Fence, HartMask, Ipi, Reset, Timer};
+use sbi_spec::{legacy::*, srst};
+
+/// 是否实现了这个遗留扩展。
+#[inline]
+pub(crate) fn probe(eid: usize) -> bool {
+    (LEGACY_SET_TIMER..=LEGACY_SHUTDOWN).contains(&eid)
+}
+
+/// 处理遗留调用 `eid`，返回 `a0`。
+///
+/// 读硬件线程掩码失败时，异常已转交给特权软件，返回 `None`，特权软件处理异常后会重新调用。
+pub(crate) fn handle(eid: usize, [a0, a1, a2, a3]: [usize; 4]) -> Option<usize> {
+    match eid {
+        LEGACY_SET_TIMER => clint::Clint.set_timer(a0 as _),
+        LEGACY_CONSOLE_PUTCHAR => {
+            console::write(&[a0 as u8]);
+            console::flush();
+        }
+        // 没有输入时返回 -1
+        LEGACY_CONSOLE_GETCHAR => {
+            console::flush();
+            return Some(uart16550::getchar().map_or(usize::MAX, |c| c as _));
+        }
+        LEGACY_CLEAR_IPI => unsafe { mip::clear_ssoft() },
+        LEGACY_SEND_IPI => {
+            clint::Clint.send_ipi(hart_mask(a0)?);
+        }
+        LEGACY_REMOTE_FENCE_I => {
+            rfence::RFence.remote_fence_i(hart_mask(a0)?);
+        }
+        LEGACY_REMOTE_SFENCE_VMA => {
+            rfence::RFence.remote_sfence_vma(hart_mask(a0)?, a1, a2);
+        }
+        LEGACY_REMOTE_SFENCE_VMA_ASID => {
+            rfence::RFence.remote_sfence_vma_asid(hart_mask(a0)?, a1, a2, a3);
+        }
+        LEGACY_SHUTDOWN => {
+            qemu_test::get().system_reset(srst::RESET_TYPE_SHUTDOWN, srst::RESET_REASON_NO_REASON);
+        }
+        _ => unreachable!(),
+    }
+    Some(0)
+}
+
+/// 读特权软件传入的硬件线程掩码，空指针表示所有硬件线程。
+///
+/// 读取失败时把异常转交给特权软件。
+fn hart_mask(ptr: usize) -> Option<HartMask> {
+    if ptr == 0 {
+        return Some(HartMask::from_mask_base(0, usize::MAX));
+    }
+    // 访存引发的陷入会修改 mepc，转交异常时 sepc 应指向 ecall
+    let pc = mepc::read();
+    let mask = misaligned::load_usize(ptr);
+    mepc::write(pc);
+    match mask {
+        Ok(mask) => Some(HartMask::from_mask_base(mask, 0)),
+        Err(Fault { cause, tval }) => {
+            trap_redirect::redirect_exception(cause, tval);
+            None
+        }
+    }
+}
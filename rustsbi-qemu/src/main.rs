@@ -3,23 +3,55 @@
 #![feature(naked_functions, asm_const)]
 #![deny(warnings)]
 
+#[macro_use]
+mod trap_detect;
+
+mod aia;
 mod clint;
+mod console;
 mod dbcn;
 mod device_tree;
+mod dtb_fixup;
+mod dynamic;
+mod envcfg;
 mod hart_csr_utils;
+mod illegal;
+mod isa;
+mod legacy;
+mod misaligned;
+mod plic;
+mod pmp;
+mod pmu;
 mod qemu_test;
+mod reboot;
+mod rfence;
 mod riscv_spec;
+mod suspend;
+mod trap_redirect;
 mod trap_stack;
 mod trap_vec;
 mod uart16550;
 
 mod constants {
-    /// 特权软件入口。
+    /// 特权软件默认入口，没有 `fw_dynamic_info` 时使用。
     pub(crate) const SUPERVISOR_ENTRY: usize = 0x8020_0000;
-    /// 每个硬件线程设置 16KiB 栈空间。
-    pub(crate) const LEN_STACK_PER_HART: usize = 16 * 1024;
-    /// qemu-virt 最多 8 个硬件线程。
-    pub(crate) const NUM_HART_MAX: usize = 8;
+    /// 每个硬件线程的栈空间，默认 16 KiB，构建时由环境变量 `STACK_SIZE` 设置。
+    pub(crate) const LEN_STACK_PER_HART: usize = parse_usize(env!("LEN_STACK_PER_HART"));
+    /// hartid 的上限，hartid 不小于此数的硬件线程停在固件里。
+    ///
+    /// 栈按设备树中的硬件线程数分配，实际能启动的硬件线程数还受固件空间限制。
+    pub(crate) const NUM_HART_MAX: usize = 96;
+
+    const fn parse_usize(s: &str) -> usize {
+        let s = s.as_bytes();
+        let mut ans = 0;
+        let mut i = 0;
+        while i < s.len() {
+            ans = ans * 10 + (s[i] - b'0') as usize;
+            i += 1;
+        }
+        ans
+    }
 }
 
 #[macro_use]
@@ -29,7 +61,7 @@ use constants::*;
 use core::{
     arch::asm,
     mem::MaybeUninit,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 use device_tree::BoardInfo;
 use fast_trap::{FastContext, FastResult};
@@ -60,37 +92,63 @@ unsafe extern "C" fn _start() -> ! {
     )
 }
 
-/// rust 入口。
-extern "C" fn rust_main(hartid: usize, opaque: usize) {
-    static GENESIS: AtomicBool = AtomicBool::new(true);
-    static BOARD_INFO: Once<BoardInfo> = Once::new();
+/// 下一个进入 [`rust_main`] 的硬件线程执行全局初始化。
+static GENESIS: AtomicBool = AtomicBool::new(true);
+/// 其他硬件线程为 `fw_dynamic_info` 指定的启动硬件线程让出启动栈的总次数。
+///
+/// 全局初始化之前 `.bss` 还没有清零，必须放在 `.data`。
+#[link_section = ".data"]
+static BOOT_YIELDS: AtomicUsize = AtomicUsize::new(0);
+/// 指定的启动硬件线程迟迟不来时，最多让出启动栈的总次数。
+const BOOT_HART_PATIENCE: usize = 4096;
+/// 板级信息。
+static BOARD_INFO: Once<BoardInfo> = Once::new();
 
+/// rust 入口。
+extern "C" fn rust_main(hartid: usize, opaque: usize, nonstandard_a2: usize) {
     // 全局初始化过程
-    if GENESIS.swap(false, Ordering::AcqRel) {
+    if elected(hartid, opaque, nonstandard_a2) && GENESIS.swap(false, Ordering::AcqRel) {
         extern "C" {
             static mut sbss: u64;
             static mut ebss: u64;
         }
         unsafe {
-            let mut ptr = sbss as *mut u64;
-            let end = ebss as *mut u64;
+            let mut ptr = core::ptr::addr_of_mut!(sbss);
+            let end = core::ptr::addr_of_mut!(ebss);
             while ptr < end {
                 ptr.write_volatile(0);
                 ptr = ptr.offset(1);
             }
         }
         // 解析设备树
-        let board_info = BOARD_INFO.call_once(|| device_tree::parse(opaque));
+        let board_info = match device_tree::parse(opaque) {
+            Ok(board_info) => BOARD_INFO.call_once(|| board_info),
+            Err(e) => fail_to_parse(e),
+        };
         // 初始化外设
         uart16550::init(board_info.uart.start);
-        rcore_console::init_console(&Console);
+        rcore_console::init_console(&console::Console);
         rcore_console::set_log_level(option_env!("LOG"));
-        clint::init(board_info.clint.start);
-        qemu_test::init(board_info.test.start);
+        // 在固件区域顶端划出设备树缓冲区，其余空间为每个硬件线程分配栈
+        let dtb_buffer = dtb_fixup::reserve(board_info.dtb.len());
+        trap_stack::init(board_info, dtb_buffer.start);
+        clint::init(board_info);
+        aia::init(board_info);
+        plic::init(board_info);
+        qemu_test::init(board_info);
+        let next_stage = dynamic::init(nonstandard_a2, &board_info.mem);
+        // 修补交给特权软件的设备树
+        let dtb = dtb_fixup::init(
+            board_info.dtb.clone(),
+            dtb_buffer,
+            board_info.mem.start..SUPERVISOR_ENTRY,
+        );
         dbcn::init(SUPERVISOR_ENTRY..board_info.mem.end);
+        pmu::init(SUPERVISOR_ENTRY..board_info.mem.end);
         // 打印启动信息
-        print!(
-            "\
+        if next_stage.options & dynamic::OPTION_NO_BOOT_PRINTS == 0 {
+            print!(
+                "\
 [rustsbi] RustSBI version {ver_sbi}, adapting to RISC-V SBI v2.0.0
 {logo}
 [rustsbi] Implementation     : RustSBI-QEMU Version {ver_impl}
@@ -100,44 +158,62 @@ extern "C" fn rust_main(hartid: usize, opaque: usize) {
 [rustsbi] Boot HART          : {hartid}
 [rustsbi] Device Tree Region : {dtb:#x?}
 [rustsbi] Firmware Address   : {firmware:#x}
-[rustsbi] Supervisor Address : {SUPERVISOR_ENTRY:#x}
+[rustsbi] Supervisor Address : {next_stage}
 ",
-            ver_sbi = rustsbi::VERSION,
-            logo = rustsbi::LOGO,
-            ver_impl = env!("CARGO_PKG_VERSION"),
-            model = board_info.model,
-            smp = board_info.smp,
-            mem = board_info.mem,
-            dtb = board_info.dtb,
-            firmware = _start as usize,
-        );
+                ver_sbi = rustsbi::VERSION,
+                logo = rustsbi::LOGO,
+                ver_impl = env!("CARGO_PKG_VERSION"),
+                model = board_info.model,
+                smp = board_info.smp,
+                mem = board_info.mem,
+                dtb = dtb,
+                firmware = _start as usize,
+            );
+            print_harts(board_info);
+        }
         // 初始化 SBI
         unsafe {
             SBI = MaybeUninit::new(FixedRustSBI {
                 clint: &clint::Clint,
+                fence: rfence::RFence,
                 hsm: Hsm,
                 reset: qemu_test::get(),
+                pmu: pmu::Pmu,
                 dbcn: dbcn::get(),
+                susp: suspend::SystemSuspend,
             });
         }
-        // 设置并打印 pmp
-        set_pmp(board_info);
-        hart_csr_utils::print_pmps();
         // 设置陷入栈
         trap_stack::prepare_for_trap();
+        // 设置 pmp，结果记录在陷入栈上的硬件线程上下文里
+        pmp::init_hart();
         // 设置内核入口
         local_remote_hsm().start(Supervisor {
-            start_addr: SUPERVISOR_ENTRY,
-            opaque,
+            start_addr: next_stage.addr,
+            opaque: dtb.start,
+            mpp: next_stage.mpp,
         });
+        // 热重启时放行其他硬件线程
+        reboot::release();
     } else {
-        // 设置 pmp
-        set_pmp(BOARD_INFO.wait());
+        // 在启动栈上的硬件线程让出启动栈，等待自己的栈分配好再进来
+        if !trap_stack::is_ready() {
+            unsafe { trap_stack::yield_boot_stack(hartid, opaque, nonstandard_a2) };
+        }
         // 设置陷入栈
         trap_stack::prepare_for_trap();
+        // 设置 pmp，结果记录在陷入栈上的硬件线程上下文里
+        pmp::init_hart();
+        reboot::rejoin();
     }
     // 清理 clint
     clint::clear();
+    envcfg::init_hart();
+    clint::init_hart();
+    aia::init_hart();
+    plic::init_hart();
+    // 停止可编程计数器
+    pmu::init_hart();
     // 准备启动调度
     unsafe {
         asm!("csrw mideleg,    {}", in(reg) !0);
@@ -146,8 +222,54 @@ extern "C" fn rust_main(hartid: usize, opaque: usize) {
         use riscv::register::{medeleg, mtvec};
         medeleg::clear_supervisor_env_call();
         medeleg::clear_machine_env_call();
+        medeleg::clear_load_misaligned();
+        medeleg::clear_store_misaligned();
+        medeleg::clear_illegal_instruction();
         mtvec::write(trap_vec as _, mtvec::TrapMode::Vectored);
     }
+    hart_csr_utils::report();
+}
+
+/// 当前硬件线程是否执行全局初始化。
+///
+/// 热重启时由发起者执行。`fw_dynamic_info` 指定的启动硬件线程只是建议：
+/// 它的 hartid 不小于 [`NUM_HART_MAX`]、不在设备树中或迟迟不来时，由最先到达的硬件线程执行。
+fn elected(hartid: usize, opaque: usize, nonstandard_a2: usize) -> bool {
+    if reboot::is_warm() {
+        return true;
+    }
+    match dynamic::read(nonstandard_a2)
+        .ok()
+        .and_then(|next_stage| next_stage.boot_hart)
+    {
+        None => true,
+        Some(id) if id == hartid => true,
+        Some(id) => {
+            id >= NUM_HART_MAX
+                || !device_tree::has_hart(opaque, id)
+                || BOOT_YIELDS.fetch_add(1, Ordering::Relaxed) >= BOOT_HART_PATIENCE
+        }
+    }
+}
+
+/// 打印设备树描述的硬件线程，描述相同的连续硬件线程合为一行。
+fn print_harts(board_info: &BoardInfo) {
+    let mut ids = board_info.hart_ids().peekable();
+    while let Some(first) = ids.next() {
+        let info = &board_info.hart_info[first];
+        let mut last = first;
+        while let Some(next) = ids.next_if(|i| board_info.hart_info[*i] == *info) {
+            last = next;
+        }
+        if first == last {
+            println!("[rustsbi] HART {first:<14}: {info}");
+        } else {
+            // 与启动信息的冒号对齐
+            let digits = |n: usize| n.checked_ilog10().unwrap_or(0) as usize + 1;
+            let pad = 13usize.saturating_sub(digits(first) + digits(last));
+            println!("[rustsbi] HART {first}-{last}{:pad$}: {info}", "");
+        }
+    }
 }
 
 #[inline(always)]
@@ -155,29 +277,41 @@ fn hart_id() -> usize {
     riscv::register::mhartid::read()
 }
 
-/// 设置 PMP。
-fn set_pmp(board_info: &BoardInfo) {
-    use riscv::register::*;
-    let mem = &board_info.mem;
-    unsafe {
-        pmpcfg0::set_pmp(0, Range::OFF, Permission::NONE, false);
-        pmpaddr0::write(0);
-        // 外设
-        pmpcfg0::set_pmp(1, Range::TOR, Permission::RW, false);
-        pmpaddr1::write(mem.start >> 2);
-        // SBI
-        pmpcfg0::set_pmp(2, Range::TOR, Permission::NONE, false);
-        pmpaddr2::write(SUPERVISOR_ENTRY >> 2);
-        // 主存
-        pmpcfg0::set_pmp(3, Range::TOR, Permission::RWX, false);
-        pmpaddr3::write(mem.end >> 2);
-        // 其他
-        pmpcfg0::set_pmp(4, Range::TOR, Permission::RW, false);
-        pmpaddr4::write(1 << (usize::BITS - 1));
+/// 打开 M 态处理的中断。
+///
+/// `timer` 表示是否代理特权软件的定时器中断。
+fn set_mie(timer: bool) {
+    let mut bits = mie::MSIE;
+    if timer {
+        bits |= mie::MTIE;
+    }
+    if aia::has_imsic() || plic::has_local_context() {
+        bits |= mie::MEIE;
     }
+    mie::write(bits);
 }
 
+/// 快速路径入口。
+///
+/// 进出时都检查栈溢出，完整路径的溢出在下次陷入时发现。
 extern "C" fn fast_handler(
+    ctx: FastContext,
+    a1: usize,
+    a2: usize,
+    a3: usize,
+    a4: usize,
+    a5: usize,
+    a6: usize,
+    a7: usize,
+) -> FastResult {
+    trap_stack::check_overflow();
+    let ans = handle_trap(ctx, a1, a2, a3, a4, a5, a6, a7);
+    trap_stack::check_overflow();
+    ans
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_trap(
     mut ctx: FastContext,
     a1: usize,
     a2: usize,
@@ -188,15 +322,20 @@ extern "C" fn fast_handler(
     a7: usize,
 ) -> FastResult {
     use riscv::register::{
-        mcause::{self, Exception as E, Trap as T},
+        mcause::{self, Exception as E, Interrupt as I, Trap as T},
         mtval, satp, sstatus,
     };
 
+    /// 从 `start_addr` 启动或恢复特权软件。
+    ///
+    /// S 态从关中断、关分页的状态开始，不可恢复挂起丢失的状态也在这里复位。
     #[inline]
     fn boot(mut ctx: FastContext, start_addr: usize, opaque: usize) -> FastResult {
         unsafe {
             sstatus::clear_sie();
+            asm!("csrw sie, zero", "csrw sscratch, zero");
             satp::write(0);
+            riscv::asm::sfence_vma_all();
         }
         ctx.regs().a[0] = hart_id();
         ctx.regs().a[1] = opaque;
@@ -208,20 +347,26 @@ extern "C" fn fast_handler(
             Ok(supervisor) => {
                 mstatus::update(|bits| {
                     *bits &= !mstatus::MPP;
-                    *bits |= mstatus::MPIE | mstatus::MPP_SUPERVISOR;
+                    *bits |= mstatus::MPIE | supervisor.mpp;
                 });
-                mie::write(mie::MSIE | mie::MTIE);
+                // 支持 Sstc 时 M 态不处理特权软件的定时器中断
+                set_mie(!clint::has_sstc());
                 break boot(ctx, supervisor.start_addr, supervisor.opaque);
             }
-            Err(rustsbi::spec::hsm::HART_STOP) => {
-                mie::write(mie::MSIE);
+            Err(rustsbi::spec::hsm::hart_state::STOPPED) => {
+                set_mie(false);
                 unsafe { riscv::asm::wfi() };
-                clint::clear_msip();
+                if clint::take_ipi() & clint::IPI_TYPE_REBOOT != 0 {
+                    reboot::park();
+                }
+                // 关闭的硬件线程也要确认远程屏障请求，否则发起者会一直等待
+                rfence::handle_local();
             }
             _ => match mcause::read().cause() {
                 // SBI call
                 T::Exception(E::SupervisorEnvCall) => {
-                    use sbi_spec::{base, hsm, legacy};
+                    use sbi_spec::{base, hsm, susp};
+                    pmu::record(pmu::FW_EVENT_SBI_CALL);
                     let mut ret = unsafe { SBI.assume_init_mut() }.handle_ecall(
                         a7,
                         a6,
@@ -229,20 +374,24 @@ extern "C" fn fast_handler(
                     );
                     if ret.is_ok() {
                         match (a7, a6) {
-                            // 关闭
-                            (hsm::EID_HSM, hsm::HART_STOP) => continue,
+                            // 关闭，离开特权软件后才完成关闭
+                            (hsm::EID_HSM, hsm::HART_STOP) => {
+                                console::flush();
+                                local_hsm().stopped().unwrap();
+                                continue;
+                            }
                             // 不可恢复挂起
                             (hsm::EID_HSM, hsm::HART_SUSPEND)
-                                if matches!(ctx.a0() as u32, hsm::suspend_type::NON_RETENTIVE) =>
+                                if suspend::is_non_retentive(ctx.a0() as u32) =>
                             {
                                 break boot(ctx, a1, a2);
                             }
-                            // legacy console 探测
+                            // 从系统挂起恢复
+                            (susp::EID_SUSP, susp::SUSPEND) => break boot(ctx, a1, a2),
+                            // 遗留扩展探测
                             (base::EID_BASE, base::PROBE_EXTENSION)
-                                if matches!(
-                                    ctx.a0(),
-                                    legacy::LEGACY_CONSOLE_PUTCHAR | legacy::LEGACY_CONSOLE_GETCHAR
-                                ) =>
+                                if legacy::probe(ctx.a0())
+                                    || ctx.a0() == hart_csr_utils::EID_RUSTSBI =>
                             {
                                 ret.value = 1;
                             }
@@ -250,22 +399,26 @@ extern "C" fn fast_handler(
                         }
                     } else {
                         match a7 {
-                            legacy::LEGACY_CONSOLE_PUTCHAR => {
-                                print!("{}", ctx.a0() as u8 as char);
-                                ret.error = 0;
-                                ret.value = a1;
-                            }
-                            legacy::LEGACY_CONSOLE_GETCHAR => {
-                                let mut c = 0u8;
-                                let uart = uart16550::UART.lock();
-                                loop {
-                                    if uart.get().read(core::slice::from_mut(&mut c)) == 1 {
-                                        ret.error = c as _;
+                            eid if legacy::probe(eid) => {
+                                match legacy::handle(eid, [ctx.a0(), a1, a2, a3]) {
+                                    Some(a0) => {
+                                        ret.error = a0;
                                         ret.value = a1;
-                                        break;
+                                    }
+                                    // 读硬件线程掩码的异常已转交给特权软件
+                                    None => {
+                                        ctx.regs().a = [ctx.a0(), a1, a2, a3, a4, a5, a6, a7];
+                                        break ctx.restore();
                                     }
                                 }
                             }
+                            hart_csr_utils::EID_RUSTSBI => {
+                                ret = hart_csr_utils::handle(a6, [ctx.a0(), a1, a2]);
+                            }
+                            // 快照共享内存不在 RustSBI 接口中
+                            sbi_spec::pmu::EID_PMU if a6 == sbi_spec::pmu::SNAPSHOT_SET_SHMEM => {
+                                ret = pmu::snapshot_set_shmem(ctx.a0(), a1, a2);
+                            }
                             _ => {}
                         }
                     }
@@ -273,6 +426,31 @@ extern "C" fn fast_handler(
                     mepc::next();
                     break ctx.restore();
                 }
+                // 核间中断，来自 msip 或 IMSIC；或者串口接收中断，来自 PLIC
+                T::Interrupt(I::MachineSoft | I::MachineExternal) => {
+                    plic::handle();
+                    handle_ipi();
+                    ctx.regs().a = [ctx.a0(), a1, a2, a3, a4, a5, a6, a7];
+                    break ctx.restore();
+                }
+                // 模拟特权软件的非对齐访存
+                T::Exception(E::LoadMisaligned | E::StoreMisaligned)
+                    if trap_redirect::from_lower_privilege() =>
+                {
+                    ctx.regs().a = [ctx.a0(), a1, a2, a3, a4, a5, a6, a7];
+                    break ctx.continue_with(misaligned::handle, ());
+                }
+                // 模拟特权软件读计数器
+                T::Exception(E::IllegalInstruction) if trap_redirect::from_lower_privilege() => {
+                    ctx.regs().a = [ctx.a0(), a1, a2, a3, a4, a5, a6, a7];
+                    break ctx.continue_with(illegal::handle, ());
+                }
+                // 来自特权软件的其他异常，转交给特权软件
+                T::Exception(_) if trap_redirect::from_lower_privilege() => {
+                    trap_redirect::redirect();
+                    ctx.regs().a = [ctx.a0(), a1, a2, a3, a4, a5, a6, a7];
+                    break ctx.restore();
+                }
                 // 其他陷入
                 trap => {
                     println!(
@@ -295,6 +473,23 @@ extern "C" fn fast_handler(
     }
 }
 
+/// 处理所有待处理的核间中断，返回核间中断类型。
+fn handle_ipi() -> usize {
+    use sbi_spec::pmu::firmware_event;
+    let ipi_type = clint::take_ipi();
+    if ipi_type & clint::IPI_TYPE_REBOOT != 0 {
+        reboot::park();
+    }
+    if ipi_type & clint::IPI_TYPE_SSOFT != 0 {
+        pmu::record(firmware_event::IPI_RECEIVED);
+        unsafe { riscv::register::mip::set_ssoft() };
+    }
+    if ipi_type & clint::IPI_TYPE_FENCE != 0 {
+        rfence::handle_local();
+    }
+    ipi_type
+}
+
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     use rustsbi::{
@@ -304,8 +499,25 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     // 输出的信息大概是“[rustsbi-panic] hart 0 panicked at ...”
     println!("[rustsbi-panic] hart {} {info}", hart_id());
     println!("[rustsbi-panic] system shutdown scheduled due to RustSBI panic");
+    console::flush();
     qemu_test::get().system_reset(RESET_TYPE_SHUTDOWN, RESET_REASON_SYSTEM_FAILURE);
-    unreachable!()
+    // 没有关机设备
+    loop {
+        unsafe { riscv::asm::wfi() };
+    }
+}
+
+/// 设备树不能解析或缺少必需的设备，有串口就报告错误，然后停在这里。
+fn fail_to_parse(e: device_tree::ParseError) -> ! {
+    if let Some(uart) = e.uart() {
+        uart16550::init(uart);
+        rcore_console::init_console(&console::Console);
+        println!("[rustsbi] failed to parse device tree: {e}");
+        console::flush();
+    }
+    loop {
+        unsafe { riscv::asm::wfi() };
+    }
 }
 
 /// 特权软件信息。
@@ -313,28 +525,8 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 struct Supervisor {
     start_addr: usize,
     opaque: usize,
-}
-
-struct Console;
-
-impl rcore_console::Console for Console {
-    #[inline]
-    fn put_char(&self, c: u8) {
-        let uart = uart16550::UART.lock();
-        while uart.get().write(&[c]) == 0 {
-            core::hint::spin_loop();
-        }
-    }
-
-    #[inline]
-    fn put_str(&self, s: &str) {
-        let uart = uart16550::UART.lock();
-        let mut bytes = s.as_bytes();
-        while !bytes.is_empty() {
-            let count = uart.get().write(bytes);
-            bytes = &bytes[count..];
-        }
-    }
+    /// 启动时的特权级，以 `mstatus.MPP` 的形式保存。
+    mpp: usize,
 }
 
 static mut SBI: MaybeUninit<FixedRustSBI> = MaybeUninit::uninit();
@@ -343,19 +535,35 @@ static mut SBI: MaybeUninit<FixedRustSBI> = MaybeUninit::uninit();
 struct FixedRustSBI<'a> {
     #[rustsbi(ipi, timer)]
     clint: &'a clint::Clint,
+    fence: rfence::RFence,
     hsm: Hsm,
     reset: &'a qemu_test::QemuTest,
+    pmu: pmu::Pmu,
     dbcn: &'a dbcn::DBCN,
+    susp: suspend::SystemSuspend,
 }
 
 struct Hsm;
 
 impl rustsbi::Hsm for Hsm {
     fn hart_start(&self, hartid: usize, start_addr: usize, opaque: usize) -> SbiRet {
+        // 设备树中禁用的硬件线程不能启动特权软件
+        let enabled = BOARD_INFO
+            .wait()
+            .hart_info
+            .get(hartid)
+            .map_or(false, |info| info.enabled);
+        if !enabled {
+            return SbiRet::invalid_param();
+        }
         match remote_hsm(hartid) {
             Some(remote) => {
-                if remote.start(Supervisor { start_addr, opaque }) {
-                    clint::set_msip(hartid);
+                if remote.start(Supervisor {
+                    start_addr,
+                    opaque,
+                    mpp: mstatus::MPP_SUPERVISOR,
+                }) {
+                    clint::wake(hartid);
                     SbiRet::success(0)
                 } else {
                     SbiRet::already_started()
@@ -367,8 +575,10 @@ impl rustsbi::Hsm for Hsm {
 
     #[inline]
     fn hart_stop(&self) -> SbiRet {
-        local_hsm().stop();
-        SbiRet::success(0)
+        match local_hsm().stop() {
+            Ok(()) => SbiRet::success(0),
+            Err(_) => SbiRet::failed(),
+        }
     }
 
     #[inline]
@@ -379,15 +589,8 @@ impl rustsbi::Hsm for Hsm {
         }
     }
 
-    fn hart_suspend(&self, suspend_type: u32, _resume_addr: usize, _opaque: usize) -> SbiRet {
-        use rustsbi::spec::hsm::suspend_type::{NON_RETENTIVE, RETENTIVE};
-        if matches!(suspend_type, NON_RETENTIVE | RETENTIVE) {
-            local_hsm().suspend();
-            unsafe { riscv::asm::wfi() };
-            local_hsm().resume();
-            SbiRet::success(0)
-        } else {
-            SbiRet::not_supported()
-        }
+    #[inline]
+    fn hart_suspend(&self, suspend_type: u32, resume_addr: usize, _opaque: usize) -> SbiRet {
+        suspend::suspend(suspend_type, resume_addr)
     }
 }
@@ -0,0 +1,337 @@
+//! 模拟特权软件的非对齐访存。
+//!
+//! 通过 `mstatus.MPRV` 以陷入前的特权级取指令和逐字节访存，
+//! 访存引发的陷入由 [`detect_entry`] 捕获并转交给特权软件。
+
+use crate::{
+    pmu,
+    riscv_spec::{mepc, mstatus},
+    trap_detect::detect_entry,
+    trap_redirect,
+};
+use core::arch::asm;
+use fast_trap::{EntireContext, EntireResult, FlowContext};
+use misaligned_emu::{Error, Hart};
+use riscv::register::{
+    mcause::{self, Exception as E, Trap as T},
+    mscratch, mtval,
+};
+use sbi_spec::pmu::firmware_event;
+
+/// 转交给特权软件的异常。
+pub(crate) struct Fault {
+    pub cause: usize,
+    pub tval: usize,
+}
+
+/// 模拟非对齐访存的完整路径。
+pub(crate) extern "C" fn handle(ctx: EntireContext) -> EntireResult {
+    let (mut ctx, _) = ctx.split();
+    let cause = mcause::read();
+    let tval = mtval::read();
+    let pc = mepc::read();
+    let store = cause.cause() == T::Exception(E::StoreMisaligned);
+    pmu::record(if store {
+        firmware_event::MISALIGNED_STORE
+    } else {
+        firmware_event::MISALIGNED_LOAD
+    });
+    let result = misaligned_emu::emulate(&mut Previous::new(ctx.regs()), pc, tval, store);
+    // 访存引发的陷入会修改 mepc
+    mepc::write(pc);
+    match result {
+        Ok(len) => mepc::write(pc + len),
+        Err(Error::Fault(fault)) => trap_redirect::redirect_exception(fault.cause, fault.tval),
+        Err(Error::Unsupported(_)) => trap_redirect::redirect_exception(cause.bits(), tval),
+    }
+    ctx.restore()
+}
+
+/// 陷入前的硬件线程。
+///
+/// 完整路径中 `sp` 在 `mscratch`，`gp` 和 `tp` 不会被固件修改，其他寄存器在上下文中。
+pub(crate) struct Previous<'a>(&'a mut FlowContext);
+
+impl<'a> Previous<'a> {
+    #[inline]
+    pub fn new(regs: &'a mut FlowContext) -> Self {
+        Self(regs)
+    }
+}
+
+impl Hart for Previous<'_> {
+    type Fault = Fault;
+
+    fn x(&self, i: u8) -> usize {
+        let regs = &self.0;
+        match i {
+            1 => regs.ra,
+            2 => mscratch::read(),
+            3 => {
+                let bits: usize;
+                unsafe { asm!("mv {}, gp", out(reg) bits) };
+                bits
+            }
+            4 => {
+                let bits: usize;
+                unsafe { asm!("mv {}, tp", out(reg) bits) };
+                bits
+            }
+            5..=7 => regs.t[i as usize - 5],
+            8..=9 => regs.s[i as usize - 8],
+            10..=17 => regs.a[i as usize - 10],
+            18..=27 => regs.s[i as usize - 16],
+            28..=31 => regs.t[i as usize - 25],
+            _ => 0,
+        }
+    }
+
+    fn set_x(&mut self, i: u8, val: usize) {
+        let regs = &mut self.0;
+        match i {
+            1 => regs.ra = val,
+            2 => mscratch::write(val),
+            3 => unsafe { asm!("mv gp, {}", in(reg) val) },
+            4 => unsafe { asm!("mv tp, {}", in(reg) val) },
+            5..=7 => regs.t[i as usize - 5] = val,
+            8..=9 => regs.s[i as usize - 8] = val,
+            10..=17 => regs.a[i as usize - 10] = val,
+            18..=27 => regs.s[i as usize - 16] = val,
+            28..=31 => regs.t[i as usize - 25] = val,
+            _ => {}
+        }
+    }
+
+    fn f(&self, i: u8) -> u64 {
+        fp::read(i)
+    }
+
+    fn set_f(&mut self, i: u8, val: u64) {
+        fp::write(i, val)
+    }
+
+    fn fetch_u16(&mut self, addr: usize) -> Result<u16, Fault> {
+        // 取指令引发的读异常转换为取指异常
+        load_u16_mxr(addr).map_err(|fault| Fault {
+            cause: match fault.cause {
+                LOAD_ACCESS_FAULT => INSTRUCTION_ACCESS_FAULT,
+                LOAD_PAGE_FAULT => INSTRUCTION_PAGE_FAULT,
+                cause => cause,
+            },
+            tval: addr,
+        })
+    }
+
+    fn load_u8(&mut self, addr: usize) -> Result<u8, Fault> {
+        let val: usize;
+        let trapped: usize;
+        unsafe {
+            asm!(
+                "   csrrw {tvec}, mtvec, {tvec}
+                    li    t0, 0
+                    csrrs {status}, mstatus, {mprv}
+                    .option push
+                    .option norvc
+                    lbu   {val}, 0({addr})
+                    .option pop
+                    csrw  mstatus, {status}
+                    csrw  mtvec, {tvec}
+                ",
+                tvec   = inout(reg) detect_entry as usize => _,
+                status = out(reg) _,
+                mprv   = in(reg) mstatus::MPRV,
+                addr   = in(reg) addr,
+                val    = out(reg) val,
+                out("t0") trapped,
+            )
+        };
+        if trapped == 0 {
+            Ok(val as _)
+        } else {
+            Err(take_fault())
+        }
+    }
+
+    fn store_u8(&mut self, addr: usize, val: u8) -> Result<(), Fault> {
+        let trapped: usize;
+        unsafe {
+            asm!(
+                "   csrrw {tvec}, mtvec, {tvec}
+                    li    t0, 0
+                    csrrs {status}, mstatus, {mprv}
+                    .option push
+                    .option norvc
+                    sb    {val}, 0({addr})
+                    .option pop
+                    csrw  mstatus, {status}
+                    csrw  mtvec, {tvec}
+                ",
+                tvec   = inout(reg) detect_entry as usize => _,
+                status = out(reg) _,
+                mprv   = in(reg) mstatus::MPRV,
+                addr   = in(reg) addr,
+                val    = in(reg) val,
+                out("t0") trapped,
+            )
+        };
+        if trapped == 0 {
+            Ok(())
+        } else {
+            Err(take_fault())
+        }
+    }
+}
+
+const INSTRUCTION_ACCESS_FAULT: usize = 1;
+const LOAD_ACCESS_FAULT: usize = 5;
+const INSTRUCTION_PAGE_FAULT: usize = 12;
+const LOAD_PAGE_FAULT: usize = 13;
+
+/// 以陷入前的特权级读 16 位，可以读只可执行的页。
+fn load_u16_mxr(addr: usize) -> Result<u16, Fault> {
+    let val: usize;
+    let trapped: usize;
+    unsafe {
+        asm!(
+            "   csrrw {tvec}, mtvec, {tvec}
+                li    t0, 0
+                csrrs {status}, mstatus, {mprv}
+                .option push
+                .option norvc
+                lhu   {val}, 0({addr})
+                .option pop
+                csrw  mstatus, {status}
+                csrw  mtvec, {tvec}
+            ",
+            tvec   = inout(reg) detect_entry as usize => _,
+            status = out(reg) _,
+            mprv   = in(reg) mstatus::MPRV | mstatus::MXR,
+            addr   = in(reg) addr,
+            val    = out(reg) val,
+            out("t0") trapped,
+        )
+    };
+    if trapped == 0 {
+        Ok(val as _)
+    } else {
+        Err(take_fault())
+    }
+}
+
+/// 以陷入前的特权级读一个 `usize`。
+///
+/// 访存引发的陷入会修改 `mepc`，由调用者保存。
+pub(crate) fn load_usize(addr: usize) -> Result<usize, Fault> {
+    let val: usize;
+    let trapped: usize;
+    unsafe {
+        asm!(
+            "   csrrw {tvec}, mtvec, {tvec}
+                li    t0, 0
+                csrrs {status}, mstatus, {mprv}
+                .option push
+                .option norvc
+                ld    {val}, 0({addr})
+                .option pop
+                csrw  mstatus, {status}
+                csrw  mtvec, {tvec}
+            ",
+            tvec   = inout(reg) detect_entry as usize => _,
+            status = out(reg) _,
+            mprv   = in(reg) mstatus::MPRV,
+            addr   = in(reg) addr,
+            val    = out(reg) val,
+            out("t0") trapped,
+        )
+    };
+    if trapped == 0 {
+        Ok(val)
+    } else {
+        Err(take_fault())
+    }
+}
+
+/// 取出刚刚捕获的陷入。
+#[inline]
+fn take_fault() -> Fault {
+    Fault {
+        cause: mcause::read().bits(),
+        tval: mtval::read(),
+    }
+}
+
+/// 通过 `fmv.x.d` 和 `fmv.d.x` 访问浮点寄存器。
+///
+/// 固件不启用浮点扩展，直接写出指令编码。
+mod fp {
+    use core::arch::asm;
+
+    /// `fmv.x.d t0, f{i}`
+    const fn fmv_x_d(i: u8) -> u32 {
+        0xe200_0053 | (i as u32) << 15 | 5 << 7
+    }
+
+    /// `fmv.d.x f{i}, t0`
+    const fn fmv_d_x(i: u8) -> u32 {
+        0xf200_0053 | 5 << 15 | (i as u32) << 7
+    }
+
+    macro_rules! for_each_fp {
+        ($i:expr, $m:ident) => {
+            match $i {
+                0 => $m!(0),
+                1 => $m!(1),
+                2 => $m!(2),
+                3 => $m!(3),
+                4 => $m!(4),
+                5 => $m!(5),
+                6 => $m!(6),
+                7 => $m!(7),
+                8 => $m!(8),
+                9 => $m!(9),
+                10 => $m!(10),
+                11 => $m!(11),
+                12 => $m!(12),
+                13 => $m!(13),
+                14 => $m!(14),
+                15 => $m!(15),
+                16 => $m!(16),
+                17 => $m!(17),
+                18 => $m!(18),
+                19 => $m!(19),
+                20 => $m!(20),
+                21 => $m!(21),
+                22 => $m!(22),
+                23 => $m!(23),
+                24 => $m!(24),
+                25 => $m!(25),
+                26 => $m!(26),
+                27 => $m!(27),
+                28 => $m!(28),
+                29 => $m!(29),
+                30 => $m!(30),
+                _ => $m!(31),
+            }
+        };
+    }
+
+    pub(super) fn read(i: u8) -> u64 {
+        let val: u64;
+        macro_rules! read {
+            ($n:literal) => {
+                unsafe { asm!(".word {}", const fmv_x_d($n), out("t0") val) }
+            };
+        }
+        for_each_fp!(i, read);
+        val
+    }
+
+    pub(super) fn write(i: u8, val: u64) {
+        macro_rules! write {
+            ($n:literal) => {
+                unsafe { asm!(".word {}", const fmv_d_x($n), in("t0") val) }
+            };
+        }
+        for_each_fp!(i, write);
+    }
+}
@@ -0,0 +1,93 @@
+//! PLIC 的 M 态上下文。
+//!
+//! 只打开串口的接收中断，由固件把收到的数据移进接收缓冲，特权软件通过 SBI 读取。
+//! 多插槽时只使用第一个插槽的 PLIC，其他插槽的硬件线程不接收串口中断。
+
+use crate::{device_tree::BoardInfo, hart_id, uart16550};
+use spin::Once;
+
+/// 中断源优先级数组的偏移。
+const PRIORITY: usize = 0x0;
+/// 上下文中断使能数组的偏移，每个上下文 0x80 字节。
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+/// 上下文阈值和认领/完成寄存器的偏移，每个上下文 0x1000 字节。
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const THRESHOLD: usize = 0x0;
+const CLAIM: usize = 0x4;
+
+struct Plic {
+    base: usize,
+    m_context: &'static [Option<usize>],
+    uart_irq: usize,
+}
+
+static PLIC: Once<Plic> = Once::new();
+
+/// 设置串口中断的优先级，并打开串口的接收中断。
+pub(crate) fn init(board_info: &'static BoardInfo) {
+    let (Some(plic), Some(uart_irq)) = (&board_info.plic, board_info.uart_irq) else {
+        return;
+    };
+    let plic = PLIC.call_once(|| Plic {
+        base: plic.range.start,
+        m_context: &plic.m_context,
+        uart_irq,
+    });
+    plic.write(PRIORITY + uart_irq * 4, 1);
+    uart16550::enable_rx_interrupt();
+}
+
+/// 在当前硬件线程的 M 态上下文中打开串口中断。
+pub(crate) fn init_hart() {
+    if let Some((plic, context)) = local() {
+        let word = ENABLE + context * ENABLE_STRIDE + plic.uart_irq / 32 * 4;
+        plic.write(word, 1 << (plic.uart_irq % 32));
+        plic.write(CONTEXT + context * CONTEXT_STRIDE + THRESHOLD, 0);
+    }
+}
+
+/// 当前硬件线程是否接收串口中断。
+#[inline]
+pub(crate) fn has_local_context() -> bool {
+    local().is_some()
+}
+
+/// 认领并处理当前硬件线程 M 态上下文中的所有中断。
+pub(crate) fn handle() {
+    let Some((plic, context)) = local() else {
+        return;
+    };
+    let claim = CONTEXT + context * CONTEXT_STRIDE + CLAIM;
+    loop {
+        match plic.read(claim) as usize {
+            0 => break,
+            irq => {
+                if irq == plic.uart_irq {
+                    uart16550::receive();
+                }
+                plic.write(claim, irq as _);
+            }
+        }
+    }
+}
+
+#[inline]
+fn local() -> Option<(&'static Plic, usize)> {
+    let plic = PLIC.get()?;
+    let context = (*plic.m_context.get(hart_id())?)?;
+    Some((plic, context))
+}
+
+impl Plic {
+    #[inline]
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+
+    #[inline]
+    fn write(&self, offset: usize, val: u32) {
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(val) }
+    }
+}
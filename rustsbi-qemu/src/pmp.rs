@@ -0,0 +1,367 @@
+//! 物理内存保护。
+//!
+//! 每个硬件线程探测自己的 PMP 表项数和粒度，按下面的顺序从高优先级到低优先级排列表项：
+//!
+//! 1. 启用 Smepmp 时保留两个表项，M 态访问特权软件的缓冲区时临时打开；
+//! 2. 设备树，在固件区域顶端的缓冲区里，特权软件只读，热重启时固件还要重新解析；
+//! 3. 固件，包括串口缓冲区等固件自己的数据；
+//! 4. 设备树描述的每个内存区域，特权软件可读写执行；
+//! 5. 其他地址都当作设备，特权软件可读写。
+//!
+//! 设备树明确描述了 Smepmp 时设置 `mseccfg.MML` 和 `mseccfg.MMWP`，
+//! 固件代码和数据锁定为 M 态专用，M 态不能执行也不能直接访问特权软件的内存。
+//! 这两位复位前不能清除，所以不按探测结果猜测。
+
+use crate::{
+    dtb_fixup,
+    envcfg::{mseccfg, CSR_MSECCFG},
+    hart_id,
+    isa::Ext,
+    trap_stack::local_mml,
+    BOARD_INFO, SUPERVISOR_ENTRY,
+};
+use core::{
+    arch::asm,
+    fmt::{self, Display, Formatter},
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use rcore_console::log;
+
+const CSR_PMPCFG0: usize = 0x3a0;
+const CSR_PMPADDR0: usize = 0x3b0;
+
+/// PMP 表项数上限。
+pub(crate) const NUM_PMP: usize = 64;
+
+pub(crate) const PMP_R: u8 = 1 << 0;
+pub(crate) const PMP_W: u8 = 1 << 1;
+pub(crate) const PMP_X: u8 = 1 << 2;
+pub(crate) const PMP_L: u8 = 1 << 7;
+
+const OFF: u8 = 0;
+const TOR: u8 = 1 << 3;
+const NAPOT: u8 = 3 << 3;
+
+/// 启用 Smepmp 时保留的表项，表项 1 以表项 0 为基址映射特权软件的缓冲区。
+const NUM_RESERVED: usize = 2;
+
+/// 所有硬件线程中最大的 PMP 粒度，映射缓冲区时按它对齐。
+static GRAIN: AtomicUsize = AtomicUsize::new(4);
+
+/// 以运行时序号读一组 CSR 中的一个，CSR 号必须是编译期常量。
+macro_rules! read_indexed {
+    ($base:expr, $i:expr, $($n:literal)*) => {
+        match $i {
+            $($n => try_read_csr!($base + $n),)*
+            _ => None,
+        }
+    };
+}
+
+/// 以运行时序号写一组 CSR 中的一个，CSR 号必须是编译期常量。
+macro_rules! write_indexed {
+    ($base:expr, $i:expr, $bits:expr, $($n:literal)*) => {
+        match $i {
+            $($n => try_write_csr!($base + $n, $bits),)*
+            _ => false,
+        }
+    };
+}
+
+/// 探测并设置当前硬件线程的 PMP。
+///
+/// 表项放不下时 panic，固件不能在没有保护的情况下启动特权软件。
+pub(crate) fn init_hart() {
+    let id = hart_id();
+    // 热重启时锁定的表项还在，布局也没有变化
+    if (0..NUM_PMP).map_while(pmpcfg).any(|cfg| cfg & PMP_L != 0) {
+        *local_mml() = try_read_csr!(CSR_MSECCFG).map_or(false, |bits| bits & mseccfg::MML != 0);
+        log::info!("hart {id} pmp: locked, keep the entries");
+        return;
+    }
+    let Some(grain) = probe_grain() else {
+        log::warn!("hart {id} pmp: not implemented, supervisor can access firmware");
+        return;
+    };
+    let entries = probe_entries();
+    GRAIN.fetch_max(grain, Ordering::Relaxed);
+    let board_info = BOARD_INFO.wait();
+    let smepmp =
+        board_info.hart_info[id].isa.has(Ext::Smepmp) && try_read_csr!(CSR_MSECCFG).is_some();
+    let plan = match Plan::build(grain, entries, smepmp, dtb_fixup::get().clone()) {
+        Ok(plan) => plan,
+        Err(e) => panic!("{e}"),
+    };
+    // 先写地址，锁定的表项会同时锁定地址
+    for (i, addr) in plan.addr[..plan.len].iter().enumerate() {
+        write_pmpaddr(i, *addr);
+    }
+    for (i, cfg) in plan.cfg[..plan.len].chunks(8).enumerate() {
+        let bits = cfg
+            .iter()
+            .rev()
+            .fold(0usize, |bits, cfg| bits << 8 | *cfg as usize);
+        write_pmpcfg(i * 2, bits);
+    }
+    if smepmp {
+        let bits = try_read_csr!(CSR_MSECCFG).unwrap_or(0) | mseccfg::MML | mseccfg::MMWP;
+        // 固件已经锁定为 M 态专用，没有 MML 时特权软件也能访问
+        let set = try_write_csr!(CSR_MSECCFG, bits)
+            && try_read_csr!(CSR_MSECCFG).map_or(false, |bits| bits & mseccfg::MML != 0);
+        assert!(set, "pmp: mseccfg.MML cannot be set");
+        *local_mml() = true;
+    }
+    log::info!(
+        "hart {id} pmp: {} of {entries} entries, grain {grain:#x}{}",
+        plan.len,
+        if smepmp { ", smepmp" } else { "" },
+    );
+}
+
+/// 临时允许 M 态访问特权软件的内存，返回值释放时撤销。
+///
+/// 没有启用 Smepmp 时 M 态本来就可以访问，什么也不做。
+/// 是否启用由 [`init_hart`] 记录，SBI 调用中不再探测 `mseccfg`。
+pub(crate) fn map_supervisor(range: Range<usize>) -> SupervisorWindow {
+    if !*local_mml() || range.is_empty() {
+        return SupervisorWindow(false);
+    }
+    let grain = GRAIN.load(Ordering::Relaxed);
+    write_pmpaddr(0, align_down(range.start, grain) >> 2);
+    write_pmpaddr(1, align_up(range.end, grain) >> 2);
+    // M 态和特权软件共享读写
+    let cfg = (TOR | PMP_W | PMP_X) as usize;
+    unsafe { asm!("csrs pmpcfg0, {}", in(reg) cfg << 8) };
+    SupervisorWindow(true)
+}
+
+/// M 态可以访问的特权软件内存，释放时关闭。
+pub(crate) struct SupervisorWindow(bool);
+
+impl Drop for SupervisorWindow {
+    fn drop(&mut self) {
+        if self.0 {
+            unsafe { asm!("csrc pmpcfg0, {}", in(reg) 0xff00) };
+        }
+    }
+}
+
+/// 表项布局。
+struct Plan {
+    cfg: [u8; NUM_PMP],
+    addr: [usize; NUM_PMP],
+    /// 需要的表项数，可能超过 [`NUM_PMP`]。
+    len: usize,
+    grain: usize,
+    /// 上一个表项是 TOR 时它的结束地址，下一个 TOR 表项可以以此为基址。
+    prev_end: Option<usize>,
+}
+
+impl Plan {
+    fn build(
+        grain: usize,
+        entries: usize,
+        smepmp: bool,
+        dtb: Range<usize>,
+    ) -> Result<Self, PlanError> {
+        let reserved = if smepmp { NUM_RESERVED } else { 0 };
+        let mut plan = Self {
+            cfg: [OFF; NUM_PMP],
+            addr: [0; NUM_PMP],
+            len: reserved,
+            grain,
+            // 表项 0 的 TOR 以 0 为基址，保留的表项地址会变化
+            prev_end: (reserved == 0).then_some(0),
+        };
+        let firmware = crate::_start as usize..SUPERVISOR_ENTRY;
+        // 设备树缓冲区在固件区域里，表项要排在固件之前；Smepmp 下 `-W-` 表示 M 态读写，特权软件只读
+        plan.protect(dtb, if smepmp { PMP_W } else { PMP_R });
+        if smepmp {
+            extern "C" {
+                static etext: u8;
+            }
+            let text_end = unsafe { core::ptr::addr_of!(etext) } as usize;
+            if text_end & (grain - 1) != 0 {
+                return Err(PlanError::Unaligned {
+                    addr: text_end,
+                    grain,
+                });
+            }
+            plan.protect(firmware.start..text_end, PMP_L | PMP_R | PMP_X);
+            plan.protect(text_end..firmware.end, PMP_L | PMP_R | PMP_W);
+        } else {
+            plan.protect(firmware, 0);
+        }
+        for range in BOARD_INFO.wait().memory.iter() {
+            let start = align_up(range.start, grain);
+            let end = align_down(range.end, grain);
+            if start < end {
+                plan.push(start..end, PMP_R | PMP_W | PMP_X);
+            }
+        }
+        // 其他地址是设备，Smepmp 下 `-WX` 表示 M 态和特权软件共享读写
+        plan.entry(
+            NAPOT | if smepmp { PMP_W | PMP_X } else { PMP_R | PMP_W },
+            usize::MAX,
+        );
+        if plan.len > entries {
+            return Err(PlanError::TooFew {
+                need: plan.len,
+                have: entries,
+            });
+        }
+        Ok(plan)
+    }
+
+    /// 保护区域向外对齐。
+    fn protect(&mut self, range: Range<usize>, perm: u8) {
+        let range = align_down(range.start, self.grain)..align_up(range.end, self.grain);
+        self.push(range, perm);
+    }
+
+    /// 区域对齐且大小是 2 的幂时用 NAPOT，否则用 TOR。
+    fn push(&mut self, range: Range<usize>, perm: u8) {
+        let size = range.end - range.start;
+        if size.is_power_of_two() && size >= 8.max(self.grain) && range.start & (size - 1) == 0 {
+            self.entry(NAPOT | perm, (range.start >> 2) | ((size >> 3) - 1));
+        } else {
+            if self.prev_end != Some(range.start) {
+                self.entry(OFF, range.start >> 2);
+            }
+            self.entry(TOR | perm, range.end >> 2);
+            self.prev_end = Some(range.end);
+        }
+    }
+
+    fn entry(&mut self, cfg: u8, addr: usize) {
+        if self.len < NUM_PMP {
+            self.cfg[self.len] = cfg;
+            self.addr[self.len] = addr;
+        }
+        self.len += 1;
+        self.prev_end = None;
+    }
+}
+
+/// PMP 规划失败。
+#[derive(Debug)]
+enum PlanError {
+    /// 表项不够。
+    TooFew { need: usize, have: usize },
+    /// 固件代码的结尾没有对齐到 PMP 粒度。
+    Unaligned { addr: usize, grain: usize },
+}
+
+impl Display for PlanError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooFew { need, have } => write!(
+                f,
+                "pmp: protecting firmware needs {need} entries, but only {have} are implemented",
+            ),
+            Self::Unaligned { addr, grain } => write!(
+                f,
+                "pmp: firmware text ends at {addr:#x}, not aligned to grain {grain:#x}",
+            ),
+        }
+    }
+}
+
+/// 探测粒度，没有实现 PMP 时返回 `None`。
+fn probe_grain() -> Option<usize> {
+    // 关闭所有表项，未锁定的表项不限制 M 态
+    for i in 0..NUM_PMP / 8 {
+        if !write_pmpcfg(i * 2, 0) {
+            break;
+        }
+    }
+    if !write_pmpaddr(0, usize::MAX) {
+        return None;
+    }
+    let bits = pmpaddr(0).filter(|bits| *bits != 0)?;
+    write_pmpaddr(0, 0);
+    Some(1 << (bits.trailing_zeros() + 2))
+}
+
+/// 探测表项数，地址寄存器不存在或只读 0 的表项没有实现。
+fn probe_entries() -> usize {
+    let mut entries = 0;
+    while entries < NUM_PMP
+        && write_pmpaddr(entries, usize::MAX)
+        && pmpaddr(entries).map_or(false, |bits| bits != 0)
+    {
+        write_pmpaddr(entries, 0);
+        entries += 1;
+    }
+    entries
+}
+
+/// PMP 表项 `i` 的配置，表项不存在时返回 `None`。
+pub(crate) fn pmpcfg(i: usize) -> Option<u8> {
+    // RV64 只有偶数号 pmpcfg，每个包含 8 个表项
+    let reg = read_indexed!(CSR_PMPCFG0, i / 8 * 2, 0 2 4 6 8 10 12 14)?;
+    // 表项存在时 pmpaddr 也可以访问
+    pmpaddr(i)?;
+    Some((reg >> (i % 8 * 8)) as u8)
+}
+
+/// PMP 表项 `i` 的地址寄存器。
+fn pmpaddr(i: usize) -> Option<usize> {
+    read_indexed!(
+        CSR_PMPADDR0, i,
+        0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15
+        16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31
+        32 33 34 35 36 37 38 39 40 41 42 43 44 45 46 47
+        48 49 50 51 52 53 54 55 56 57 58 59 60 61 62 63
+    )
+}
+
+/// 写 `pmpcfg{i}`，RV64 只有偶数号。
+fn write_pmpcfg(i: usize, bits: usize) -> bool {
+    write_indexed!(CSR_PMPCFG0, i, bits, 0 2 4 6 8 10 12 14)
+}
+
+fn write_pmpaddr(i: usize, bits: usize) -> bool {
+    write_indexed!(
+        CSR_PMPADDR0, i, bits,
+        0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15
+        16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31
+        32 33 34 35 36 37 38 39 40 41 42 43 44 45 46 47
+        48 49 50 51 52 53 54 55 56 57 58 59 60 61 62 63
+    )
+}
+
+/// PMP 表项 `i` 保护的地址范围，表项关闭时返回 `None`。
+pub(crate) fn pmp_range(i: usize, cfg: u8) -> Option<Range<usize>> {
+    let addr = pmpaddr(i)?;
+    match (cfg >> 3) & 0b11 {
+        // TOR
+        0b01 => {
+            let start = if i == 0 { 0 } else { pmpaddr(i - 1)? };
+            Some(start << 2..addr << 2)
+        }
+        // NA4
+        0b10 => Some(addr << 2..(addr + 1) << 2),
+        // NAPOT
+        0b11 => match 1usize.checked_shl(addr.trailing_ones() + 3) {
+            Some(len) => {
+                let start = (addr << 2) & !(len - 1);
+                Some(start..start.wrapping_add(len))
+            }
+            // 整个地址空间
+            None => Some(0..usize::MAX),
+        },
+        _ => None,
+    }
+}
+
+#[inline]
+const fn align_down(addr: usize, grain: usize) -> usize {
+    addr & !(grain - 1)
+}
+
+#[inline]
+const fn align_up(addr: usize, grain: usize) -> usize {
+    addr.saturating_add(grain - 1) & !(grain - 1)
+}
@@ -0,0 +1,473 @@
+use crate::{pmp, trap_stack::local_pmu};
+use core::{arch::asm, mem::size_of, ops::Range};
+use rustsbi::SbiRet;
+use sbi_spec::pmu::{cache_event, cache_operation, cache_result, event_type, firmware_event};
+use spin::Once;
+
+pub(crate) struct Pmu;
+
+/// 固件计数器的数量。
+const NUM_FW_COUNTERS: usize = 16;
+/// 硬件计数器最多 31 个：mcycle、minstret 和 mhpmcounter3..=31。
+const NUM_HW_COUNTERS_MAX: usize = 31;
+
+/// 用 `firmware_event::PLATFORM` 事件统计处理的 SBI 调用次数。
+pub(crate) const FW_EVENT_SBI_CALL: usize = firmware_event::PLATFORM;
+
+mod flags {
+    pub const CFG_SKIP_MATCH: usize = 1 << 0;
+    pub const CFG_CLEAR_VALUE: usize = 1 << 1;
+    pub const CFG_AUTO_START: usize = 1 << 2;
+
+    pub const START_SET_INIT_VALUE: usize = 1 << 0;
+    pub const START_INIT_SNAPSHOT: usize = 1 << 1;
+
+    pub const STOP_RESET: usize = 1 << 0;
+    pub const STOP_TAKE_SNAPSHOT: usize = 1 << 1;
+}
+
+/// QEMU 支持的硬件事件编码。
+///
+/// QEMU 直接采用 SBI 事件编号作为 `mhpmevent` 的值。
+const QEMU_EVENTS: [usize; 5] = [
+    event_idx(event_type::HARDWARE_GENERAL, 1), // CPU_CYCLES
+    event_idx(event_type::HARDWARE_GENERAL, 2), // INSTRUCTIONS
+    cache_event_idx(cache_event::DTLB, cache_operation::READ, cache_result::MISS),
+    cache_event_idx(
+        cache_event::DTLB,
+        cache_operation::WRITE,
+        cache_result::MISS,
+    ),
+    cache_event_idx(cache_event::ITLB, cache_operation::READ, cache_result::MISS),
+];
+
+const fn event_idx(ty: usize, code: usize) -> usize {
+    ty << 16 | code
+}
+
+const fn cache_event_idx(id: usize, op: usize, result: usize) -> usize {
+    event_idx(event_type::HARDWARE_CACHE, id << 3 | op << 1 | result)
+}
+
+/// 实现了的硬件计数器。
+///
+/// 逻辑编号 `i` 的硬件计数器是 `mcycle + csr[i]`，固件计数器排在硬件计数器之后。
+struct HwCounters {
+    csr: [u8; NUM_HW_COUNTERS_MAX],
+    len: usize,
+}
+
+static HW_COUNTERS: Once<HwCounters> = Once::new();
+static SHMEM_RANGE: Once<Range<usize>> = Once::new();
+
+/// 探测实现了的硬件计数器。
+///
+/// `memory` 是允许作为快照共享内存的范围。
+pub(crate) fn init(memory: Range<usize>) {
+    SHMEM_RANGE.call_once(|| memory);
+    HW_COUNTERS.call_once(|| {
+        let mut ans = HwCounters {
+            csr: [0; NUM_HW_COUNTERS_MAX],
+            len: 0,
+        };
+        write_mcountinhibit(!0);
+        for i in (0..32).filter(|i| *i != 1) {
+            if probe_counter(i) {
+                write_counter(i, 0);
+                ans.csr[ans.len] = i as _;
+                ans.len += 1;
+            }
+        }
+        ans
+    });
+}
+
+/// 初始化当前硬件线程的计数器。
+///
+/// 停止所有可编程计数器，保留 mcycle 和 minstret 计数。
+pub(crate) fn init_hart() {
+    write_mcountinhibit(!0b101);
+}
+
+/// 记录一次固件事件。
+#[inline]
+pub(crate) fn record(event: usize) {
+    let state = local_pmu();
+    let hw = hw_counters().len;
+    for i in 0..NUM_FW_COUNTERS {
+        if state.started & (1 << (hw + i)) != 0 && state.fw_event[i] == event {
+            state.fw_value[i] += 1;
+        }
+    }
+}
+
+/// 设置当前硬件线程的快照共享内存。
+///
+/// 规范没有定义任何标志位，`flags` 必须是 0。
+pub(crate) fn snapshot_set_shmem(lo: usize, hi: usize, flags: usize) -> SbiRet {
+    if flags != 0 {
+        return SbiRet::invalid_param();
+    }
+    let state = local_pmu();
+    if lo == usize::MAX && hi == usize::MAX {
+        state.snapshot = None;
+        return SbiRet::success(0);
+    }
+    if lo & (SNAPSHOT_SIZE - 1) != 0 {
+        return SbiRet::invalid_param();
+    }
+    let range = SHMEM_RANGE.wait();
+    if hi != 0 || !range.contains(&lo) || !range.contains(&(lo + SNAPSHOT_SIZE - 1)) {
+        return SbiRet::invalid_address();
+    }
+    state.snapshot = Some(lo);
+    SbiRet::success(0)
+}
+
+/// 快照共享内存大小。
+const SNAPSHOT_SIZE: usize = 4096;
+
+/// 快照共享内存布局。
+#[repr(C)]
+struct Snapshot {
+    overflow: u64,
+    values: [u64; 64],
+}
+
+/// 每个硬件线程的计数器状态。
+pub(crate) struct PmuState {
+    /// 已配置的逻辑计数器。
+    configured: u64,
+    /// 已启动的逻辑计数器。
+    started: u64,
+    fw_event: [usize; NUM_FW_COUNTERS],
+    fw_value: [u64; NUM_FW_COUNTERS],
+    snapshot: Option<usize>,
+}
+
+impl PmuState {
+    pub const fn new() -> Self {
+        Self {
+            configured: 0,
+            started: 0,
+            fw_event: [0; NUM_FW_COUNTERS],
+            fw_value: [0; NUM_FW_COUNTERS],
+            snapshot: None,
+        }
+    }
+}
+
+/// 逻辑计数器。
+#[derive(Clone, Copy)]
+enum Counter {
+    /// 硬件计数器，值为 `mcycle` 起的偏移。
+    Hardware(usize),
+    /// 固件计数器，值为固件计数器序号。
+    Firmware(usize),
+}
+
+/// 读 `mcycle` 起偏移 `offset` 的硬件计数器，计数器未实现返回 `None`。
+pub(crate) fn read_hw_counter(offset: usize) -> Option<usize> {
+    let hw = hw_counters();
+    hw.csr[..hw.len]
+        .iter()
+        .any(|csr| *csr as usize == offset)
+        .then(|| read_counter(offset))
+}
+
+#[inline]
+fn hw_counters() -> &'static HwCounters {
+    HW_COUNTERS.wait()
+}
+
+#[inline]
+fn num_counters() -> usize {
+    hw_counters().len + NUM_FW_COUNTERS
+}
+
+fn counter(idx: usize) -> Option<Counter> {
+    let hw = hw_counters();
+    if idx < hw.len {
+        Some(Counter::Hardware(hw.csr[idx] as _))
+    } else if idx < hw.len + NUM_FW_COUNTERS {
+        Some(Counter::Firmware(idx - hw.len))
+    } else {
+        None
+    }
+}
+
+/// 遍历 `base` 和 `mask` 选中的逻辑计数器。
+///
+/// `base` 来自特权软件，选中的序号溢出时返回 `None`。
+fn selected(base: usize, mask: usize) -> Option<impl Iterator<Item = usize> + Clone> {
+    if let Some(last) = mask.checked_ilog2() {
+        base.checked_add(last as usize)?;
+    }
+    Some(
+        (0..usize::BITS as usize)
+            .filter(move |i| mask & (1 << i) != 0)
+            .map(move |i| base + i),
+    )
+}
+
+impl Counter {
+    /// 判断计数器能否计数 `event`，能则返回要写入 `mhpmevent` 的值。
+    fn matches(&self, event: usize, data: u64) -> Option<usize> {
+        match (*self, event >> 16) {
+            (Counter::Firmware(_), event_type::FIRMWARE) => {
+                let code = event & 0xffff;
+                (code <= firmware_event::HFENCE_VVMA_ASID_RECEIVED
+                    || code == firmware_event::PLATFORM)
+                    .then_some(code)
+            }
+            (Counter::Hardware(0), _) if event == QEMU_EVENTS[0] => Some(0),
+            (Counter::Hardware(2), _) if event == QEMU_EVENTS[1] => Some(0),
+            (Counter::Hardware(i), event_type::HARDWARE_GENERAL | event_type::HARDWARE_CACHE)
+                if i >= 3 && QEMU_EVENTS.contains(&event) =>
+            {
+                Some(event)
+            }
+            (Counter::Hardware(i), event_type::HARDWARE_RAW) if i >= 3 => Some(data as _),
+            _ => None,
+        }
+    }
+}
+
+impl rustsbi::Pmu for Pmu {
+    #[inline]
+    fn num_counters(&self) -> usize {
+        num_counters()
+    }
+
+    fn counter_get_info(&self, counter_idx: usize) -> SbiRet {
+        const CSR_CYCLE: usize = 0xc00;
+        const WIDTH_64: usize = 63 << 12;
+        const TYPE_FIRMWARE: usize = 1 << (usize::BITS - 1);
+        match counter(counter_idx) {
+            Some(Counter::Hardware(i)) => SbiRet::success((CSR_CYCLE + i) | WIDTH_64),
+            Some(Counter::Firmware(_)) => SbiRet::success(TYPE_FIRMWARE),
+            None => SbiRet::invalid_param(),
+        }
+    }
+
+    fn counter_config_matching(
+        &self,
+        counter_idx_base: usize,
+        counter_idx_mask: usize,
+        config_flags: usize,
+        event_idx: usize,
+        event_data: u64,
+    ) -> SbiRet {
+        let Some(mut selected) = selected(counter_idx_base, counter_idx_mask) else {
+            return SbiRet::invalid_param();
+        };
+        let state = local_pmu();
+        if selected.clone().any(|i| i >= num_counters()) {
+            return SbiRet::invalid_param();
+        }
+        let found = if config_flags & flags::CFG_SKIP_MATCH != 0 {
+            // 调用者保证计数器已经配置过
+            selected
+                .find(|i| state.configured & (1 << i) != 0)
+                .map(|i| (i, None))
+        } else {
+            selected
+                .filter(|i| state.configured & (1 << i) == 0)
+                .find_map(|i| {
+                    counter(i)
+                        .unwrap()
+                        .matches(event_idx, event_data)
+                        .map(|value| (i, Some(value)))
+                })
+        };
+        let Some((idx, value)) = found else {
+            return SbiRet::not_supported();
+        };
+        let counter = counter(idx).unwrap();
+        if let Some(value) = value {
+            match counter {
+                Counter::Hardware(i) if i >= 3 => write_event(i, value),
+                Counter::Hardware(_) => {}
+                Counter::Firmware(i) => state.fw_event[i] = value,
+            }
+            state.configured |= 1 << idx;
+        }
+        if config_flags & flags::CFG_CLEAR_VALUE != 0 {
+            set_value(state, counter, 0);
+        }
+        if config_flags & flags::CFG_AUTO_START != 0 {
+            start(state, idx, counter);
+        }
+        SbiRet::success(idx)
+    }
+
+    fn counter_start(
+        &self,
+        counter_idx_base: usize,
+        counter_idx_mask: usize,
+        start_flags: usize,
+        initial_value: u64,
+    ) -> SbiRet {
+        let Some(selected) = selected(counter_idx_base, counter_idx_mask) else {
+            return SbiRet::invalid_param();
+        };
+        let state = local_pmu();
+        let snapshot = state.snapshot;
+        if start_flags & flags::START_INIT_SNAPSHOT != 0 && snapshot.is_none() {
+            return SbiRet::no_shmem();
+        }
+        for idx in selected {
+            let Some(counter) = counter(idx) else {
+                return SbiRet::invalid_param();
+            };
+            if state.configured & (1 << idx) == 0 {
+                return SbiRet::invalid_param();
+            }
+            if state.started & (1 << idx) != 0 {
+                return SbiRet::already_started();
+            }
+            if start_flags & flags::START_INIT_SNAPSHOT != 0 {
+                let snapshot = snapshot.unwrap();
+                let _window = pmp::map_supervisor(snapshot..snapshot + size_of::<Snapshot>());
+                let shmem = unsafe { &*(snapshot as *const Snapshot) };
+                set_value(state, counter, shmem.values[idx]);
+            } else if start_flags & flags::START_SET_INIT_VALUE != 0 {
+                set_value(state, counter, initial_value);
+            }
+            start(state, idx, counter);
+        }
+        SbiRet::success(0)
+    }
+
+    fn counter_stop(
+        &self,
+        counter_idx_base: usize,
+        counter_idx_mask: usize,
+        stop_flags: usize,
+    ) -> SbiRet {
+        let Some(selected) = selected(counter_idx_base, counter_idx_mask) else {
+            return SbiRet::invalid_param();
+        };
+        let state = local_pmu();
+        let snapshot = state.snapshot;
+        if stop_flags & flags::STOP_TAKE_SNAPSHOT != 0 && snapshot.is_none() {
+            return SbiRet::no_shmem();
+        }
+        for idx in selected {
+            let Some(counter) = counter(idx) else {
+                return SbiRet::invalid_param();
+            };
+            if state.started & (1 << idx) == 0 {
+                return SbiRet::already_stopped();
+            }
+            state.started &= !(1 << idx);
+            if let Counter::Hardware(i) = counter {
+                write_mcountinhibit(read_mcountinhibit() | 1 << i);
+            }
+            if stop_flags & flags::STOP_TAKE_SNAPSHOT != 0 {
+                let snapshot = snapshot.unwrap();
+                let _window = pmp::map_supervisor(snapshot..snapshot + size_of::<Snapshot>());
+                let shmem = unsafe { &mut *(snapshot as *mut Snapshot) };
+                shmem.overflow = 0;
+                shmem.values[idx] = value(state, counter);
+            }
+            if stop_flags & flags::STOP_RESET != 0 {
+                state.configured &= !(1 << idx);
+                match counter {
+                    Counter::Hardware(i) if i >= 3 => write_event(i, 0),
+                    Counter::Hardware(_) => {}
+                    Counter::Firmware(i) => state.fw_event[i] = 0,
+                }
+            }
+        }
+        SbiRet::success(0)
+    }
+
+    fn counter_fw_read(&self, counter_idx: usize) -> SbiRet {
+        match counter(counter_idx) {
+            Some(Counter::Firmware(i)) => SbiRet::success(local_pmu().fw_value[i] as _),
+            _ => SbiRet::invalid_param(),
+        }
+    }
+}
+
+fn start(state: &mut PmuState, idx: usize, counter: Counter) {
+    state.started |= 1 << idx;
+    if let Counter::Hardware(i) = counter {
+        write_mcountinhibit(read_mcountinhibit() & !(1 << i));
+    }
+}
+
+fn value(state: &PmuState, counter: Counter) -> u64 {
+    match counter {
+        Counter::Hardware(i) => read_counter(i) as _,
+        Counter::Firmware(i) => state.fw_value[i],
+    }
+}
+
+fn set_value(state: &mut PmuState, counter: Counter, value: u64) {
+    match counter {
+        Counter::Hardware(i) => write_counter(i, value as _),
+        Counter::Firmware(i) => state.fw_value[i] = value,
+    }
+}
+
+#[inline]
+fn read_mcountinhibit() -> usize {
+    let bits: usize;
+    unsafe { asm!("csrr {}, mcountinhibit", out(reg) bits) };
+    bits
+}
+
+#[inline]
+fn write_mcountinhibit(bits: usize) {
+    unsafe { asm!("csrw mcountinhibit, {}", in(reg) bits) };
+}
+
+/// 按编号访问 `mcycle`、`minstret`、`mhpmcounter3..=31` 和 `mhpmevent3..=31`。
+macro_rules! hpm_csr {
+    ($($i:literal)+) => {
+        /// 判断计数器是否实现。
+        ///
+        /// 未实现的计数器可能是只读 0，也可能访问时引发非法指令异常。
+        fn probe_counter(i: usize) -> bool {
+            match i {
+                0 | 2 => true,
+                $($i => {
+                    try_write_csr!(0xb00 + $i, usize::MAX)
+                        && try_read_csr!(0xb00 + $i).map_or(false, |bits| bits != 0)
+                })+
+                _ => false,
+            }
+        }
+
+        fn read_counter(i: usize) -> usize {
+            let bits: usize;
+            match i {
+                0 => unsafe { asm!("csrr {}, mcycle", out(reg) bits) },
+                2 => unsafe { asm!("csrr {}, minstret", out(reg) bits) },
+                $($i => unsafe { asm!("csrr {}, {csr}", out(reg) bits, csr = const 0xb00 + $i) },)+
+                _ => unreachable!(),
+            }
+            bits
+        }
+
+        fn write_counter(i: usize, bits: usize) {
+            match i {
+                0 => unsafe { asm!("csrw mcycle, {}", in(reg) bits) },
+                2 => unsafe { asm!("csrw minstret, {}", in(reg) bits) },
+                $($i => unsafe { asm!("csrw {csr}, {}", in(reg) bits, csr = const 0xb00 + $i) },)+
+                _ => unreachable!(),
+            }
+        }
+
+        fn write_event(i: usize, bits: usize) {
+            match i {
+                $($i => unsafe { asm!("csrw {csr}, {}", in(reg) bits, csr = const 0x320 + $i) },)+
+                _ => unreachable!(),
+            }
+        }
+    };
+}
+
+hpm_csr!(3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31);
@@ -1,3 +1,4 @@
+use crate::device_tree::{BoardInfo, Syscon};
 use rustsbi::{
     spec::srst::{
         RESET_REASON_NO_REASON, RESET_REASON_SYSTEM_FAILURE, RESET_TYPE_COLD_REBOOT,
@@ -8,12 +9,23 @@ use rustsbi::{
 use sifive_test_device::SifiveTestDevice;
 use spin::Once;
 
-pub(crate) struct QemuTest(usize);
+/// 关机和重启设备。
+///
+/// 优先使用 SiFive 测试设备，它能报告关机原因；没有时使用 `syscon-poweroff` 和 `syscon-reboot`。
+pub(crate) struct QemuTest {
+    test: Option<usize>,
+    poweroff: Option<Syscon>,
+    reboot: Option<Syscon>,
+}
 
 static TEST: Once<QemuTest> = Once::new();
 
-pub(crate) fn init(base: usize) {
-    TEST.call_once(|| QemuTest(base));
+pub(crate) fn init(board_info: &BoardInfo) {
+    TEST.call_once(|| QemuTest {
+        test: board_info.test.as_ref().map(|test| test.start),
+        poweroff: board_info.poweroff,
+        reboot: board_info.reboot,
+    });
 }
 
 pub(crate) fn get() -> &'static QemuTest {
@@ -22,18 +34,29 @@ pub(crate) fn get() -> &'static QemuTest {
 
 impl Reset for QemuTest {
     fn system_reset(&self, reset_type: u32, reset_reason: u32) -> SbiRet {
-        let test = unsafe { &*(TEST.wait().0 as *const SifiveTestDevice) };
-        match reset_type {
-            RESET_TYPE_SHUTDOWN => match reset_reason {
+        let test = self
+            .test
+            .map(|base| unsafe { &*(base as *const SifiveTestDevice) });
+        crate::console::flush();
+        match (reset_type, test) {
+            (RESET_TYPE_SHUTDOWN, Some(test)) => match reset_reason {
                 RESET_REASON_NO_REASON => test.pass(),
                 RESET_REASON_SYSTEM_FAILURE => test.fail(-1 as _),
                 value => test.fail(value as _),
             },
-            RESET_TYPE_COLD_REBOOT | RESET_TYPE_WARM_REBOOT => {
-                // test.reset();
-                todo!()
-            }
+            (RESET_TYPE_SHUTDOWN, None) => self.poweroff.map_or(SbiRet::not_supported(), write),
+            (RESET_TYPE_COLD_REBOOT, Some(test)) => test.reset(),
+            (RESET_TYPE_COLD_REBOOT, None) => self.reboot.map_or(SbiRet::not_supported(), write),
+            (RESET_TYPE_WARM_REBOOT, _) => crate::reboot::warm_reboot(),
             _ => SbiRet::invalid_param(),
         }
     }
 }
+
+/// 写关机或重启寄存器，等待设备生效。
+fn write(syscon: Syscon) -> SbiRet {
+    unsafe { (syscon.addr as *mut u32).write_volatile(syscon.value) };
+    loop {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,103 @@
+//! 热重启。
+//!
+//! 发起热重启的硬件线程通过核间中断让其他硬件线程停在固件里，
+//! 然后重新执行全局初始化过程，初始化完成后再放行其他硬件线程。
+//! 被放行的硬件线程重新执行各自的初始化，全部完成后才进入特权软件，
+//! 以免特权软件启动尚未初始化的硬件线程。
+
+use crate::{
+    clint::{self, IPI_TYPE_REBOOT},
+    dtb_fixup, dynamic, hart_id, rfence, BOARD_INFO, GENESIS,
+};
+use core::{
+    arch::asm,
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+/// 停在固件里等待重启的硬件线程数。
+///
+/// 全局初始化会清零 `.bss`，这个计数必须放在 `.data`。
+#[link_section = ".data"]
+static PARKED: AtomicUsize = AtomicUsize::new(0);
+
+/// 正在热重启，发起者执行全局初始化。
+#[link_section = ".data"]
+static WARM: AtomicBool = AtomicBool::new(false);
+
+/// 全局初始化已完成，停在固件里的硬件线程可以重新初始化。
+#[link_section = ".data"]
+static RELEASE: AtomicBool = AtomicBool::new(false);
+
+/// 热重启：停止其他硬件线程，重新执行全局初始化并进入特权软件。
+pub(crate) fn warm_reboot() -> ! {
+    let board_info = BOARD_INFO.wait();
+    let current = hart_id();
+    // 没有栈的硬件线程一直停在固件入口，收不到核间中断，不必等待
+    let receivers = board_info
+        .hart_ids()
+        .filter(|i| *i != current)
+        .filter(|i| clint::send_ipi_typed(*i, IPI_TYPE_REBOOT))
+        .count();
+    // 其他硬件线程可能正在等待当前硬件线程确认远程屏障
+    while PARKED.load(Ordering::Acquire) != receivers {
+        rfence::handle_local();
+        spin_loop();
+    }
+    WARM.store(true, Ordering::Release);
+    GENESIS.store(true, Ordering::Release);
+    restart(current, dtb_fixup::get().start, dynamic::get().info)
+}
+
+/// 停在固件里，直到发起热重启的硬件线程完成全局初始化。
+pub(crate) fn park() -> ! {
+    PARKED.fetch_add(1, Ordering::AcqRel);
+    while !RELEASE.load(Ordering::Acquire) {
+        spin_loop();
+    }
+    restart(hart_id(), 0, 0)
+}
+
+/// 全局初始化完成，放行停在固件里的硬件线程，并等待它们完成初始化。
+///
+/// 冷启动时没有停在固件里的硬件线程，直接返回。
+pub(crate) fn release() {
+    WARM.store(false, Ordering::Release);
+    RELEASE.store(true, Ordering::Release);
+    while PARKED.load(Ordering::Acquire) != 0 {
+        spin_loop();
+    }
+    RELEASE.store(false, Ordering::Release);
+}
+
+/// 是否正在热重启。
+#[inline]
+pub(crate) fn is_warm() -> bool {
+    WARM.load(Ordering::Acquire)
+}
+
+/// 被放行的硬件线程完成了初始化。
+#[inline]
+pub(crate) fn rejoin() {
+    if RELEASE.load(Ordering::Acquire) {
+        PARKED.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// 关闭中断，回到固件入口。
+fn restart(hartid: usize, opaque: usize, nonstandard_a2: usize) -> ! {
+    use riscv::register::mip;
+    crate::mie::write(0);
+    unsafe {
+        mip::clear_ssoft();
+        mip::clear_stimer();
+        asm!(
+            "j {entry}",
+            entry = sym crate::_start,
+            in("a0") hartid,
+            in("a1") opaque,
+            in("a2") nonstandard_a2,
+            options(noreturn),
+        )
+    }
+}
@@ -0,0 +1,328 @@
+use crate::{
+    clint::{self, IPI_TYPE_FENCE},
+    hart_id, pmu,
+    trap_stack::{local_rfence, remote_hsm, remote_rfence},
+    BOARD_INFO,
+};
+use core::{
+    arch::asm,
+    hint::spin_loop,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use rustsbi::{Fence, HartMask, SbiRet};
+use sbi_spec::pmu::firmware_event;
+use spin::Mutex;
+
+pub(crate) struct RFence;
+
+/// 每个硬件线程的远程屏障状态。
+pub(crate) struct RFenceCell {
+    /// 其他硬件线程发给此硬件线程的请求。
+    queue: Mutex<Queue>,
+    /// 此硬件线程发出、尚未被确认的请求数。
+    wait: AtomicUsize,
+}
+
+/// 页大小，按页刷新地址翻译缓存。
+const PAGE_SIZE: usize = 4096;
+/// 超过这个页数的范围直接全部刷新。
+const FLUSH_ALL_PAGES: usize = 64;
+/// 每个硬件线程最多同时收到的请求数。
+///
+/// 队列满时发起者先处理自己收到的请求再重试，因此不必为每个硬件线程留一个位置。
+const QUEUE_LEN: usize = 8;
+
+/// 远程屏障操作。
+#[derive(Clone, Copy)]
+enum FenceOp {
+    FenceI,
+    SFenceVma,
+    SFenceVmaAsid(usize),
+    HFenceGvma,
+    HFenceGvmaVmid(usize),
+    HFenceVvma,
+    HFenceVvmaAsid(usize),
+}
+
+impl FenceOp {
+    /// 发出请求对应的固件事件，确认请求的事件总是紧随其后。
+    fn event(&self) -> usize {
+        use firmware_event::*;
+        match self {
+            Self::FenceI => FENCE_I_SENT,
+            Self::SFenceVma => SFENCE_VMA_SENT,
+            Self::SFenceVmaAsid(_) => SFENCE_VMA_ASID_SENT,
+            Self::HFenceGvma => HFENCE_GVMA_SENT,
+            Self::HFenceGvmaVmid(_) => HFENCE_GVMA_VMID_SENT,
+            Self::HFenceVvma => HFENCE_VVMA_SENT,
+            Self::HFenceVvmaAsid(_) => HFENCE_VVMA_ASID_SENT,
+        }
+    }
+}
+
+/// 一个远程屏障请求。
+#[derive(Clone, Copy)]
+struct RFenceContext {
+    op: FenceOp,
+    start_addr: usize,
+    size: usize,
+    /// 发起请求的硬件线程的 `hgatp`，用于 `hfence.vvma`。
+    hgatp: usize,
+}
+
+/// 请求队列。
+///
+/// 发起者在请求被确认之前阻塞，因此每个发起者在一个目标上最多只有一个请求。
+struct Queue([Option<(RFenceContext, usize)>; QUEUE_LEN]);
+
+impl RFenceCell {
+    pub const fn new() -> Self {
+        Self {
+            queue: Mutex::new(Queue([None; QUEUE_LEN])),
+            wait: AtomicUsize::new(0),
+        }
+    }
+
+    /// 向此硬件线程放入一个来自 `source` 的请求，队列满返回 `false`。
+    fn push(&self, ctx: RFenceContext, source: usize) -> bool {
+        let mut queue = self.queue.lock();
+        match queue.0.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some((ctx, source));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 取出一个请求。
+    fn pop(&self) -> Option<(RFenceContext, usize)> {
+        self.queue.lock().0.iter_mut().find_map(|slot| slot.take())
+    }
+}
+
+/// 处理发给当前硬件线程的所有远程屏障请求，并通知发起者。
+pub(crate) fn handle_local() {
+    let cell = local_rfence();
+    while let Some((ctx, source)) = cell.pop() {
+        pmu::record(ctx.op.event() + 1);
+        ctx.execute();
+        if let Some(source) = remote_rfence(source) {
+            source.wait.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+impl RFenceContext {
+    #[inline]
+    fn new(op: FenceOp, start_addr: usize, size: usize) -> Self {
+        let hgatp = match op {
+            FenceOp::HFenceVvma | FenceOp::HFenceVvmaAsid(_) => {
+                let bits: usize;
+                unsafe { asm!("csrr {}, 0x680", out(reg) bits) };
+                bits
+            }
+            _ => 0,
+        };
+        Self {
+            op,
+            start_addr,
+            size,
+            hgatp,
+        }
+    }
+
+    /// 在当前硬件线程上执行屏障。
+    fn execute(&self) {
+        use FenceOp::*;
+        match self.op {
+            FenceI => unsafe { asm!("fence.i") },
+            SFenceVma => self.for_each_page(
+                || unsafe { asm!("sfence.vma") },
+                |addr| unsafe { asm!("sfence.vma {}, zero", in(reg) addr) },
+            ),
+            SFenceVmaAsid(asid) => self.for_each_page(
+                || unsafe { asm!("sfence.vma zero, {}", in(reg) asid) },
+                |addr| unsafe { asm!("sfence.vma {}, {}", in(reg) addr, in(reg) asid) },
+            ),
+            // hfence.gvma 的地址操作数是客户物理地址右移 2 位
+            HFenceGvma => self.for_each_page(
+                || unsafe { hfence_gvma(0, 0) },
+                |addr| unsafe { hfence_gvma(addr >> 2, 0) },
+            ),
+            HFenceGvmaVmid(vmid) => self.for_each_page(
+                || unsafe { hfence_gvma(0, vmid) },
+                |addr| unsafe { hfence_gvma(addr >> 2, vmid) },
+            ),
+            // hfence.vvma 作用于发起者当前的 VMID
+            HFenceVvma => self.with_hgatp(|| {
+                self.for_each_page(
+                    || unsafe { hfence_vvma(0, 0) },
+                    |addr| unsafe { hfence_vvma(addr, 0) },
+                )
+            }),
+            HFenceVvmaAsid(asid) => self.with_hgatp(|| {
+                self.for_each_page(
+                    || unsafe { hfence_vvma(0, asid) },
+                    |addr| unsafe { hfence_vvma(addr, asid) },
+                )
+            }),
+        }
+    }
+
+    /// 范围过大或表示全部地址时整体刷新，否则逐页刷新。
+    fn for_each_page(&self, all: impl Fn(), page: impl Fn(usize)) {
+        let flush_all = (self.start_addr == 0 && self.size == 0)
+            || self.size == usize::MAX
+            || self.size > FLUSH_ALL_PAGES * PAGE_SIZE;
+        if flush_all {
+            all();
+        } else {
+            let end = self.start_addr.saturating_add(self.size);
+            let mut addr = self.start_addr & !(PAGE_SIZE - 1);
+            while addr < end {
+                page(addr);
+                addr += PAGE_SIZE;
+            }
+        }
+    }
+
+    /// 临时换入发起者的 `hgatp` 执行 `f`。
+    fn with_hgatp(&self, f: impl FnOnce()) {
+        let hgatp: usize;
+        unsafe { asm!("csrrw {}, 0x680, {}", out(reg) hgatp, in(reg) self.hgatp) };
+        f();
+        unsafe { asm!("csrw 0x680, {}", in(reg) hgatp) };
+    }
+}
+
+/// `hfence.gvma rs1, rs2`
+#[inline(always)]
+unsafe fn hfence_gvma(gaddr: usize, vmid: usize) {
+    asm!(".insn r 0x73, 0, 0x31, x0, {}, {}", in(reg) gaddr, in(reg) vmid);
+}
+
+/// `hfence.vvma rs1, rs2`
+#[inline(always)]
+unsafe fn hfence_vvma(vaddr: usize, asid: usize) {
+    asm!(".insn r 0x73, 0, 0x11, x0, {}, {}", in(reg) vaddr, in(reg) asid);
+}
+
+#[inline]
+fn has_hypervisor() -> bool {
+    riscv::register::misa::read().map_or(false, |isa| isa.has_extension('H'))
+}
+
+/// 向 `hart_mask` 中的所有硬件线程发出请求，并等待它们全部确认。
+fn remote_fence(hart_mask: HartMask, ctx: RFenceContext) -> SbiRet {
+    let current = hart_id();
+    let local = local_rfence();
+    let mut this_hart = false;
+    for i in BOARD_INFO.wait().harts_in(hart_mask) {
+        if i == current {
+            this_hart = true;
+            continue;
+        }
+        if !remote_hsm(i).map_or(false, |hsm| hsm.allow_ipi()) {
+            continue;
+        }
+        let remote = remote_rfence(i).unwrap();
+        pmu::record(ctx.op.event());
+        local.wait.fetch_add(1, Ordering::AcqRel);
+        // 目标的队列满时先处理自己收到的请求，避免互相等待
+        while !remote.push(ctx, current) {
+            handle_local();
+            spin_loop();
+        }
+        clint::send_ipi_typed(i, IPI_TYPE_FENCE);
+    }
+    if this_hart {
+        ctx.execute();
+    }
+    while local.wait.load(Ordering::Acquire) != 0 {
+        handle_local();
+        spin_loop();
+    }
+    SbiRet::success(0)
+}
+
+impl Fence for RFence {
+    #[inline]
+    fn remote_fence_i(&self, hart_mask: HartMask) -> SbiRet {
+        remote_fence(hart_mask, RFenceContext::new(FenceOp::FenceI, 0, 0))
+    }
+
+    #[inline]
+    fn remote_sfence_vma(&self, hart_mask: HartMask, start_addr: usize, size: usize) -> SbiRet {
+        remote_fence(
+            hart_mask,
+            RFenceContext::new(FenceOp::SFenceVma, start_addr, size),
+        )
+    }
+
+    #[inline]
+    fn remote_sfence_vma_asid(
+        &self,
+        hart_mask: HartMask,
+        start_addr: usize,
+        size: usize,
+        asid: usize,
+    ) -> SbiRet {
+        remote_fence(
+            hart_mask,
+            RFenceContext::new(FenceOp::SFenceVmaAsid(asid), start_addr, size),
+        )
+    }
+
+    fn remote_hfence_gvma_vmid(
+        &self,
+        hart_mask: HartMask,
+        start_addr: usize,
+        size: usize,
+        vmid: usize,
+    ) -> SbiRet {
+        if !has_hypervisor() {
+            return SbiRet::not_supported();
+        }
+        remote_fence(
+            hart_mask,
+            RFenceContext::new(FenceOp::HFenceGvmaVmid(vmid), start_addr, size),
+        )
+    }
+
+    fn remote_hfence_gvma(&self, hart_mask: HartMask, start_addr: usize, size: usize) -> SbiRet {
+        if !has_hypervisor() {
+            return SbiRet::not_supported();
+        }
+        remote_fence(
+            hart_mask,
+            RFenceContext::new(FenceOp::HFenceGvma, start_addr, size),
+        )
+    }
+
+    fn remote_hfence_vvma_asid(
+        &self,
+        hart_mask: HartMask,
+        start_addr: usize,
+        size: usize,
+        asid: usize,
+    ) -> SbiRet {
+        if !has_hypervisor() {
+            return SbiRet::not_supported();
+        }
+        remote_fence(
+            hart_mask,
+            RFenceContext::new(FenceOp::HFenceVvmaAsid(asid), start_addr, size),
+        )
+    }
+
+    fn remote_hfence_vvma(&self, hart_mask: HartMask, start_addr: usize, size: usize) -> SbiRet {
+        if !has_hypervisor() {
+            return SbiRet::not_supported();
+        }
+        remote_fence(
+            hart_mask,
+            RFenceContext::new(FenceOp::HFenceVvma, start_addr, size),
+        )
+    }
+}
@@ -0,0 +1,184 @@
+//! 硬件线程挂起。
+//!
+//! 挂起的硬件线程只被 `sie` 中打开的中断或发给特权软件的核间中断唤醒。
+//! 平台自定义的挂起类型模拟更深的挂起状态，进入和退出时忙等相应的延迟。
+//! 延迟取自设备树 `/cpus/idle-states`，设备树中没有描述挂起状态时使用 [`DEFAULT_STATES`]。
+//!
+//! 系统挂起要求其他硬件线程都已关闭，调用者停在固件里，直到定时器到期或串口收到数据。
+
+use crate::{
+    clint, console, device_tree::IdleState, handle_ipi, hart_id, plic, riscv_spec::mie, set_mie,
+    trap_stack::local_hsm, trap_stack::remote_hsm, uart16550, BOARD_INFO,
+};
+use core::hint::spin_loop;
+use riscv::register::{mip, sie, sip};
+use rustsbi::SbiRet;
+use sbi_spec::hsm::{
+    hart_state,
+    suspend_type::{NON_RETENTIVE, RETENTIVE},
+};
+
+/// 挂起到内存。
+const SUSPEND_TO_RAM: u32 = 0;
+
+/// 平台自定义保持状态的挂起类型起点。
+const PLATFORM_RETENTIVE: u32 = 0x1000_0000;
+/// 平台自定义丢失状态的挂起类型起点。
+const PLATFORM_NON_RETENTIVE: u32 = 0x9000_0000;
+
+/// 设备树中没有描述挂起状态时模拟的平台自定义挂起状态。
+const DEFAULT_STATES: [IdleState; 2] = [
+    // 保持状态的浅挂起
+    IdleState {
+        suspend_type: Some(PLATFORM_RETENTIVE),
+        entry_us: 10,
+        exit_us: 20,
+    },
+    // 丢失状态的深挂起
+    IdleState {
+        suspend_type: Some(PLATFORM_NON_RETENTIVE),
+        entry_us: 200,
+        exit_us: 500,
+    },
+];
+
+/// 设备树中没有 `timebase-frequency` 时使用 QEMU virt 的 10 MHz。
+const DEFAULT_TIMEBASE: usize = 10_000_000;
+
+/// 挂起类型是否丢失状态。
+#[inline]
+pub(crate) fn is_non_retentive(suspend_type: u32) -> bool {
+    suspend_type & NON_RETENTIVE != 0
+}
+
+/// 挂起当前硬件线程，被唤醒后返回。
+///
+/// 丢失状态的挂起成功返回后，由调用者从 `resume_addr` 恢复特权软件。
+pub(crate) fn suspend(suspend_type: u32, resume_addr: usize) -> SbiRet {
+    let state = find(suspend_type);
+    let valid = match suspend_type {
+        RETENTIVE | NON_RETENTIVE => true,
+        // 平台自定义的挂起类型必须有对应的挂起状态
+        PLATFORM_RETENTIVE..=0x7fff_ffff | PLATFORM_NON_RETENTIVE..=u32::MAX => state.is_some(),
+        // 保留的挂起类型
+        _ => false,
+    };
+    if !valid {
+        return SbiRet::invalid_param();
+    }
+    if is_non_retentive(suspend_type) && !is_supervisor_memory(resume_addr) {
+        return SbiRet::invalid_address();
+    }
+    let (entry_us, exit_us) = state.map_or((0, 0), |s| (s.entry_us, s.exit_us));
+    console::flush();
+    let hsm = local_hsm();
+    if hsm.suspend().is_err() {
+        return SbiRet::failed();
+    }
+    delay_us(entry_us);
+    hsm.suspended().unwrap();
+    wait_for_wakeup();
+    hsm.resume().unwrap();
+    delay_us(exit_us);
+    hsm.resumed().unwrap();
+    SbiRet::success(0)
+}
+
+/// 等待 `sie` 中打开的中断，或发给特权软件的核间中断。
+///
+/// 远程屏障请求在等待期间处理，不唤醒硬件线程。
+fn wait_for_wakeup() {
+    loop {
+        unsafe { riscv::asm::wfi() };
+        // 挂起发生在 SBI 调用中，M 态中断没有打开，在这里代替中断处理
+        clint::forward_timer();
+        plic::handle();
+        let ipi_type = handle_ipi();
+        if ipi_type & clint::IPI_TYPE_SSOFT != 0 || sip::read().bits() & sie::read().bits() != 0 {
+            break;
+        }
+    }
+}
+
+/// 系统挂起。
+pub(crate) struct SystemSuspend;
+
+impl rustsbi::Susp for SystemSuspend {
+    /// 挂起整个系统，被唤醒后返回。
+    ///
+    /// 成功返回后，由调用者从 `resume_addr` 恢复特权软件。
+    fn system_suspend(&self, sleep_type: u32, resume_addr: usize, _opaque: usize) -> SbiRet {
+        if sleep_type != SUSPEND_TO_RAM {
+            return SbiRet::invalid_param();
+        }
+        if !is_supervisor_memory(resume_addr) {
+            return SbiRet::invalid_address();
+        }
+        let board_info = BOARD_INFO.wait();
+        let current = hart_id();
+        let others_stopped = board_info
+            .hart_ids()
+            .filter(|i| *i != current)
+            .all(|i| remote_hsm(i).map_or(true, |hsm| hsm.sbi_get_status() == hart_state::STOPPED));
+        if !others_stopped {
+            return SbiRet::denied();
+        }
+        console::flush();
+        let hsm = local_hsm();
+        if hsm.suspend().is_err() {
+            return SbiRet::failed();
+        }
+        hsm.suspended().unwrap();
+        // 内存在挂起期间保持，只需打开唤醒源等待：
+        // 转给特权软件的定时器中断、Sstc 的 `mip.STIP`、来自 PLIC 的串口接收中断和核间中断
+        let saved = riscv::register::mie::read().bits();
+        set_mie(!clint::has_sstc());
+        mie::write(riscv::register::mie::read().bits() | mie::STIE);
+        loop {
+            unsafe { riscv::asm::wfi() };
+            // 同 `wait_for_wakeup`，M 态中断没有打开，在这里代替中断处理
+            clint::forward_timer();
+            plic::handle();
+            handle_ipi();
+            if mip::read().stimer() || uart16550::has_input() {
+                break;
+            }
+        }
+        mie::write(saved);
+        hsm.resume().unwrap();
+        hsm.resumed().unwrap();
+        SbiRet::success(0)
+    }
+}
+
+/// 找到挂起类型对应的挂起状态。
+fn find(suspend_type: u32) -> Option<IdleState> {
+    let states = &BOARD_INFO.wait().idle_states;
+    let matches = |s: &&IdleState| s.suspend_type == Some(suspend_type);
+    if states.is_empty() {
+        DEFAULT_STATES.iter().find(matches).copied()
+    } else {
+        states.iter().find(matches).copied()
+    }
+}
+
+/// 地址是否在交给特权软件的内存中。
+fn is_supervisor_memory(addr: usize) -> bool {
+    let mem = &BOARD_INFO.wait().mem;
+    mem.contains(&addr) && addr >= crate::SUPERVISOR_ENTRY
+}
+
+/// 忙等 `us` 微秒。
+fn delay_us(us: u32) {
+    if us == 0 {
+        return;
+    }
+    let timebase = match BOARD_INFO.wait().timebase {
+        0 => DEFAULT_TIMEBASE,
+        f => f,
+    };
+    let end = clint::mtime() + us as u64 * timebase as u64 / 1_000_000;
+    while clint::mtime() < end {
+        spin_loop();
+    }
+}
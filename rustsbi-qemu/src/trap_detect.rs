@@ -0,0 +1,120 @@
+//! 机器态陷入检测。
+//!
+//! 探测可能不存在的 CSR 时，临时将 `mtvec` 换成 [`detect_entry`]。
+//! 发生陷入时跳过引发陷入的指令，并将 `t0` 置为 1。
+//!
+//! [`detect_entry`] 的 `mret` 会修改 `mepc` 和 `mstatus`，探测前后保存并恢复这两个 CSR，
+//! 因此处理陷入的过程中也可以探测。`mcause` 和 `mtval` 不恢复，必须在探测前读出。
+
+/// 读 CSR，如果访问引发陷入返回 `None`。
+macro_rules! try_read_csr {
+    ($csr:expr) => {{
+        let bits: usize;
+        let trapped: usize;
+        unsafe {
+            core::arch::asm!(
+                "   csrr  {epc}, mepc
+                    csrr  {status}, mstatus
+                    csrrw {tvec}, mtvec, {tvec}
+                    li    t0, 0
+                    csrr  {bits}, {csr}
+                    csrw  mtvec, {tvec}
+                    csrw  mstatus, {status}
+                    csrw  mepc, {epc}
+                ",
+                tvec   = inout(reg) $crate::trap_detect::detect_entry as usize => _,
+                epc    = out(reg) _,
+                status = out(reg) _,
+                bits   = out(reg) bits,
+                csr    = const $csr,
+                out("t0") trapped,
+            )
+        };
+        if trapped == 0 {
+            Some(bits)
+        } else {
+            None
+        }
+    }};
+}
+
+/// 写 CSR，如果访问引发陷入返回 `false`。
+macro_rules! try_write_csr {
+    ($csr:expr, $bits:expr) => {{
+        let trapped: usize;
+        unsafe {
+            core::arch::asm!(
+                "   csrr  {epc}, mepc
+                    csrr  {status}, mstatus
+                    csrrw {tvec}, mtvec, {tvec}
+                    li    t0, 0
+                    csrw  {csr}, {bits}
+                    csrw  mtvec, {tvec}
+                    csrw  mstatus, {status}
+                    csrw  mepc, {epc}
+                ",
+                tvec   = inout(reg) $crate::trap_detect::detect_entry as usize => _,
+                epc    = out(reg) _,
+                status = out(reg) _,
+                bits   = in(reg) $bits,
+                csr    = const $csr,
+                out("t0") trapped,
+            )
+        };
+        trapped == 0
+    }};
+}
+
+/// 读内存，如果访问引发陷入返回 `None`。
+///
+/// 关闭压缩指令，以保证陷入时跳过的正好是访存指令。
+pub(crate) fn try_read_usize(addr: usize) -> Option<usize> {
+    let bits: usize;
+    let trapped: usize;
+    unsafe {
+        core::arch::asm!(
+            "   csrr  {epc}, mepc
+                csrr  {status}, mstatus
+                csrrw {tvec}, mtvec, {tvec}
+                li    t0, 0
+                .option push
+                .option norvc
+                ld    {bits}, 0({addr})
+                .option pop
+                csrw  mtvec, {tvec}
+                csrw  mstatus, {status}
+                csrw  mepc, {epc}
+            ",
+            tvec   = inout(reg) detect_entry as usize => _,
+            epc    = out(reg) _,
+            status = out(reg) _,
+            addr   = in(reg) addr,
+            bits   = out(reg) bits,
+            out("t0") trapped,
+        )
+    };
+    if trapped == 0 {
+        Some(bits)
+    } else {
+        None
+    }
+}
+
+/// 陷入检测入口。
+///
+/// # Safety
+///
+/// 裸函数，只能由 [`try_read_csr`]、[`try_write_csr`] 和 [`try_read_usize`] 使用。
+#[naked]
+pub(crate) unsafe extern "C" fn detect_entry() {
+    core::arch::asm!(
+        ".align 2",
+        "   csrr t0, mepc
+            addi t0, t0, 4
+            csrw mepc, t0
+            li   t0, 1
+            mret
+        ",
+        options(noreturn)
+    )
+}
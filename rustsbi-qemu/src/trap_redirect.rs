@@ -0,0 +1,50 @@
+//! 把来自 S 态或 U 态、固件不处理的陷入转交给特权软件。
+
+use crate::riscv_spec::{mepc, mstatus};
+use riscv::register::{mcause, mtval, scause, sepc, stval, stvec};
+
+/// 陷入是否来自 S 态或 U 态。
+#[inline]
+pub(crate) fn from_lower_privilege() -> bool {
+    mstatus::read() & mstatus::MPP != mstatus::MPP_MACHINE
+}
+
+/// 陷入是否来自 S 态。
+#[inline]
+pub(crate) fn from_supervisor() -> bool {
+    mstatus::read() & mstatus::MPP == mstatus::MPP_SUPERVISOR
+}
+
+/// 模拟特权软件收到了当前陷入。
+#[inline]
+pub(crate) fn redirect() {
+    redirect_exception(mcause::read().bits(), mtval::read());
+}
+
+/// 模拟特权软件在 `mepc` 处收到了 `cause` 异常。
+///
+/// 设置 `scause`、`sepc`、`stval` 和 `sstatus`，并令 `mret` 返回到 `stvec`。
+/// 只转交异常，因此不考虑向量模式。
+pub(crate) fn redirect_exception(cause: usize, tval: usize) {
+    unsafe {
+        scause::write(cause);
+        sepc::write(mepc::read());
+        stval::write(tval);
+    }
+    mstatus::update(|bits| {
+        // 进入陷入时 SIE 保存到 SPIE 并关闭，SPP 记录陷入前的特权级
+        let spie = if *bits & mstatus::SIE != 0 {
+            mstatus::SPIE
+        } else {
+            0
+        };
+        let spp = if *bits & mstatus::MPP == mstatus::MPP_SUPERVISOR {
+            mstatus::SPP
+        } else {
+            0
+        };
+        *bits &= !(mstatus::SIE | mstatus::SPIE | mstatus::SPP | mstatus::MPP);
+        *bits |= spie | spp | mstatus::MPP_SUPERVISOR;
+    });
+    mepc::write(stvec::read().address());
+}
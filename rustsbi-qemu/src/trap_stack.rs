@@ -1,73 +1,257 @@
-﻿use crate::{fast_handler, hart_id, Supervisor, LEN_STACK_PER_HART, NUM_HART_MAX};
-use core::{mem::forget, ptr::NonNull};
+﻿use crate::{
+    device_tree::BoardInfo, fast_handler, hart_id, pmu::PmuState, rfence::RFenceCell, Supervisor,
+    LEN_STACK_PER_HART, NUM_HART_MAX,
+};
+use core::{
+    mem::{forget, size_of, size_of_val},
+    ptr::{addr_of, null_mut, NonNull},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+};
 use fast_trap::{FlowContext, FreeTrapStack};
 use hsm_cell::{HsmCell, LocalHsmCell, RemoteHsmCell};
+use rcore_console::log;
 
-/// 栈空间。
+/// 硬件线程上下文表，以 hartid 为下标，指向每个硬件线程的栈。
+///
+/// 全局初始化时从固件空间中为设备树中的每个硬件线程分配栈。
+/// 热重启会清零 `.bss`，但各硬件线程的栈不变，这个表必须放在 `.data`。
+#[link_section = ".data"]
+static mut HART_TABLE: [*mut Stack; NUM_HART_MAX] = [null_mut(); NUM_HART_MAX];
+
+/// 上下文表已经建立。
+#[link_section = ".data"]
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// 启动栈的锁。
+#[link_section = ".data"]
+static BOOT_LOCK: AtomicU32 = AtomicU32::new(0);
+
+/// 启动栈。
+///
+/// 上下文表建立之前，硬件线程轮流在这个栈上判断自己是否执行全局初始化。
 #[link_section = ".bss.uninit"]
-static mut ROOT_STACK: [Stack; NUM_HART_MAX] = [Stack::ZERO; NUM_HART_MAX];
+static mut BOOT_STACK: Stack = Stack::ZERO;
+
+/// 在 `end` 以下为设备树中的每个硬件线程分配栈并建立上下文表。
+///
+/// 当前硬件线程总是最先分配，其他硬件线程的栈不足时停在固件里。
+/// 每个停在固件里的硬件线程都报告一条警告，包括 hartid 超出 [`NUM_HART_MAX`] 的硬件线程。
+/// 热重启时上下文表已经建立，不再分配。
+pub(crate) fn init(board_info: &BoardInfo, end: usize) {
+    extern "C" {
+        static sstack: u8;
+    }
+    if READY.load(Ordering::Acquire) {
+        return;
+    }
+    let parked = &board_info.parked_harts;
+    for id in parked.iter() {
+        log::warn!("hartid {id} is not less than {NUM_HART_MAX}, it will stay in firmware");
+    }
+    if parked.unlisted() > 0 {
+        log::warn!(
+            "{} more harts with hartid not less than {NUM_HART_MAX} will stay in firmware",
+            parked.unlisted()
+        );
+    }
+    unsafe {
+        let mut ptr = addr_of!(sstack) as usize;
+        let current = hart_id();
+        let others = board_info.hart_ids().filter(|id| *id != current);
+        for id in core::iter::once(current).chain(others) {
+            if ptr + LEN_STACK_PER_HART > end {
+                log::warn!("no space for the stack of hart {id}, it will stay in firmware");
+                continue;
+            }
+            HART_TABLE[id] = ptr as _;
+            ptr += LEN_STACK_PER_HART;
+        }
+    }
+    READY.store(true, Ordering::Release);
+}
+
+/// 上下文表是否已经建立。
+#[inline]
+pub(crate) fn is_ready() -> bool {
+    READY.load(Ordering::Acquire)
+}
 
 /// 定位每个 hart 的栈。
+///
+/// 上下文表建立前，硬件线程争用启动栈；建立后，没有栈的硬件线程永远停在这里。
 #[naked]
 pub(crate) unsafe extern "C" fn locate() {
     core::arch::asm!(
-        "   la   sp, {stack}
+        "   csrr t1, mhartid
+            li   t0, {num_hart_max}
+            bgeu t1, t0, 3f
+            la   t0, {table}
+            slli t1, t1, 3
+            add  t1, t1, t0
+         1: la   t2, {ready}
+            lb   t2, (t2)
+            fence r, r
+            ld   t0, (t1)
+            bnez t0, 2f
+            bnez t2, 3f
+            la   t2, {boot_lock}
+            li   t3, 1
+            amoswap.w.aq t3, t3, (t2)
+            bnez t3, 1b
+            la   sp, {boot_stack}
             li   t0, {per_hart_stack_size}
-            csrr t1, mhartid
-            addi t1, t1,  1
-         1: add  sp, sp, t0
-            addi t1, t1, -1
-            bnez t1, 1b
+            add  sp, sp, t0
+            ret
+         2: li   sp, {per_hart_stack_size}
+            add  sp, sp, t0
             call t1, {move_stack}
             ret
+         3: wfi
+            j    3b
         ",
+        num_hart_max        = const NUM_HART_MAX,
         per_hart_stack_size = const LEN_STACK_PER_HART,
-        stack               =   sym ROOT_STACK,
+        table               =   sym HART_TABLE,
+        ready               =   sym READY,
+        boot_lock           =   sym BOOT_LOCK,
+        boot_stack          =   sym BOOT_STACK,
         move_stack          =   sym fast_trap::reuse_stack_for_trap,
         options(noreturn),
     )
 }
 
+/// 让出启动栈，重新定位栈。
+///
+/// # Safety
+///
+/// 当前硬件线程必须持有启动栈，并且此后不再使用启动栈上的任何数据。
+pub(crate) unsafe fn yield_boot_stack(hartid: usize, opaque: usize, nonstandard_a2: usize) -> ! {
+    core::arch::asm!(
+        "   amoswap.w.rl zero, zero, ({boot_lock})
+            j    {entry}
+        ",
+        boot_lock = in(reg) addr_of!(BOOT_LOCK),
+        entry     = sym crate::_start,
+        in("a0") hartid,
+        in("a1") opaque,
+        in("a2") nonstandard_a2,
+        options(noreturn),
+    )
+}
+
 /// 预备陷入栈。
 pub(crate) fn prepare_for_trap() {
-    unsafe { ROOT_STACK.get_unchecked_mut(hart_id()).load_as_stack() };
+    local_stack().load_as_stack();
 }
 
 /// 获取此 hart 的 local hsm 对象。
 pub(crate) fn local_hsm() -> LocalHsmCell<'static, Supervisor> {
-    unsafe {
-        ROOT_STACK
-            .get_unchecked_mut(hart_id())
-            .hart_context()
-            .hsm
-            .local()
-    }
+    unsafe { local_context().hsm.local() }
 }
 
 /// 获取此 hart 的 remote hsm 对象。
 pub(crate) fn local_remote_hsm() -> RemoteHsmCell<'static, Supervisor> {
-    unsafe {
-        ROOT_STACK
-            .get_unchecked_mut(hart_id())
-            .hart_context()
-            .hsm
-            .remote()
-    }
+    local_context().hsm.remote()
 }
 
 /// 获取任意 hart 的 remote hsm 对象。
 pub(crate) fn remote_hsm(hart_id: usize) -> Option<RemoteHsmCell<'static, Supervisor>> {
-    unsafe {
-        ROOT_STACK
-            .get_mut(hart_id)
-            .map(|x| x.hart_context().hsm.remote())
+    remote_context(hart_id).map(|x| x.hsm.remote())
+}
+
+/// 获取此 hart 待处理的核间中断类型。
+pub(crate) fn local_ipi() -> &'static AtomicUsize {
+    &local_context().ipi
+}
+
+/// 获取任意 hart 待处理的核间中断类型。
+pub(crate) fn remote_ipi(hart_id: usize) -> Option<&'static AtomicUsize> {
+    remote_context(hart_id).map(|x| &x.ipi)
+}
+
+/// 获取此 hart 的远程屏障对象。
+pub(crate) fn local_rfence() -> &'static RFenceCell {
+    &local_context().rfence
+}
+
+/// 获取任意 hart 的远程屏障对象。
+pub(crate) fn remote_rfence(hart_id: usize) -> Option<&'static RFenceCell> {
+    remote_context(hart_id).map(|x| &x.rfence)
+}
+
+/// 获取此 hart 的性能计数器状态。
+pub(crate) fn local_pmu() -> &'static mut PmuState {
+    &mut local_context().pmu
+}
+
+/// 此 hart 是否用 `stimecmp` 实现定时器。
+pub(crate) fn local_sstc() -> &'static mut bool {
+    &mut local_context().sstc
+}
+
+/// 此 hart 是否设置了 `mseccfg.MML`，M 态不能直接访问特权软件的内存。
+pub(crate) fn local_mml() -> &'static mut bool {
+    &mut local_context().mml
+}
+
+/// 获取此 hart 的栈。
+#[inline]
+fn local_stack() -> &'static mut Stack {
+    unsafe { &mut **HART_TABLE.get_unchecked(hart_id()) }
+}
+
+/// 检查此 hart 的栈是否溢出到硬件线程状态。
+///
+/// 栈溢出会先覆盖硬件线程状态上方的保护区，发现保护区被改写时报告溢出深度并停机。
+#[inline]
+pub(crate) fn check_overflow() {
+    let guard = local_stack().guard();
+    if let Some(i) = guard.iter().position(|word| *word != CANARY) {
+        let into_guard = (GUARD_WORDS - i) * size_of::<usize>();
+        let usable = LEN_STACK_PER_HART - size_of::<HartContext>() - size_of_val(guard);
+        if i == 0 {
+            panic!(
+                "trap stack overflow: more than {} bytes used out of {usable}, hart state may be corrupted",
+                usable + into_guard,
+            )
+        } else {
+            panic!(
+                "trap stack overflow: {} bytes used out of {usable}",
+                usable + into_guard,
+            )
+        }
     }
 }
 
+/// 获取此 hart 的上下文。
+#[inline]
+fn local_context() -> &'static mut HartContext {
+    local_stack().hart_context()
+}
+
+/// 获取分配了栈的 hart 的上下文。
+fn remote_context(hart_id: usize) -> Option<&'static mut HartContext> {
+    let ptr = unsafe { *HART_TABLE.get(hart_id)? };
+    unsafe { ptr.as_mut() }.map(Stack::hart_context)
+}
+
+/// 保护区字数。
+const GUARD_WORDS: usize = 32;
+/// 保护区填充的值。
+const CANARY: usize = 0xdead_beef_cafe_f00d;
+/// 除去硬件线程状态和保护区，栈至少要留给陷入处理的空间。
+const MIN_USABLE_STACK: usize = 2048;
+
+const _: () = assert!(
+    LEN_STACK_PER_HART
+        > size_of::<HartContext>() + GUARD_WORDS * size_of::<usize>() + MIN_USABLE_STACK,
+    "STACK_SIZE too small for the hart state, the guard and trap handling",
+);
+
 /// 类型化栈。
 ///
-/// 每个硬件线程拥有一个满足这样条件的内存块。
-/// 这个内存块的底部放着硬件线程状态 [`HartContext`]，顶部用于陷入处理，中间是这个硬件线程的栈空间。
+/// 每个硬件线程拥有一个满足这样条件的内存块，在全局初始化时从固件空间中分配。
+/// 这个内存块的底部放着硬件线程状态 [`HartContext`] 和保护区，顶部用于陷入处理，中间是这个硬件线程的栈空间。
 /// 不需要 M 态线程，每个硬件线程只有这一个栈。
 #[repr(C, align(128))]
 struct Stack([u8; LEN_STACK_PER_HART]);
@@ -82,7 +266,14 @@ impl Stack {
         unsafe { &mut *self.0.as_mut_ptr().cast() }
     }
 
+    /// 硬件线程状态上方的保护区。
+    #[inline]
+    fn guard(&mut self) -> &mut [usize; GUARD_WORDS] {
+        unsafe { &mut *self.0.as_mut_ptr().add(size_of::<HartContext>()).cast() }
+    }
+
     fn load_as_stack(&'static mut self) {
+        self.guard().fill(CANARY);
         let hart = self.hart_context();
         let context_ptr = hart.context_ptr();
         hart.init();
@@ -105,12 +296,27 @@ struct HartContext {
     /// 陷入上下文。
     trap: FlowContext,
     hsm: HsmCell<Supervisor>,
+    /// 待处理的核间中断类型。
+    ipi: AtomicUsize,
+    /// 远程屏障请求。
+    rfence: RFenceCell,
+    /// 性能计数器状态。
+    pmu: PmuState,
+    /// 是否支持 Sstc 扩展。
+    sstc: bool,
+    /// 是否设置了 `mseccfg.MML`。
+    mml: bool,
 }
 
 impl HartContext {
     #[inline]
     fn init(&mut self) {
         self.hsm = HsmCell::new();
+        self.ipi = AtomicUsize::new(0);
+        self.rfence = RFenceCell::new();
+        self.pmu = PmuState::new();
+        self.sstc = false;
+        self.mml = false;
     }
 
     #[inline]
@@ -1,5 +1,4 @@
-use crate::clint::CLINT;
-use aclint::SifiveClint as Clint;
+use crate::{clint::HARTS, device_tree::HartLocal};
 use core::arch::asm;
 use fast_trap::trap_entry;
 
@@ -17,7 +16,7 @@ pub(crate) unsafe extern "C" fn trap_vec() {
         "j {default}", // exception
         "j {default}", // supervisor software
         "j {default}", // reserved
-        "j {msoft} ",  // machine    software
+        "j {default}", // machine    software
         "j {default}", // reserved
         "j {default}", // supervisor timer
         "j {default}", // reserved
@@ -29,7 +28,6 @@ pub(crate) unsafe extern "C" fn trap_vec() {
         ".option pop",
         default = sym trap_entry,
         mtimer  = sym mtimer,
-        msoft   = sym msoft,
         options(noreturn)
     )
 }
@@ -47,29 +45,28 @@ unsafe extern "C" fn mtimer() {
         // mscratch: S sp
         "   csrrw sp, mscratch, sp",
         // 保护
-        "   addi  sp, sp, -4*8
-            sd    ra, 0*8(sp)
-            sd    a0, 1*8(sp)
-            sd    a1, 2*8(sp)
-            sd    a2, 3*8(sp)
+        "   addi  sp, sp, -2*8
+            sd    a0, 0*8(sp)
+            sd    a1, 1*8(sp)
         ",
-        // 清除 mtimecmp
-        "   la    a0, {clint_ptr}
+        // 清除 mtimecmp：HARTS[mhartid].mtimecmp = -1
+        "   la    a0, {harts}
             ld    a0, (a0)
             csrr  a1, mhartid
-            addi  a2, zero, -1
-            call  {set_mtimecmp}
+            slli  a1, a1, {hart_local_bits}
+            add   a0, a0, a1
+            ld    a0, {mtimecmp}(a0)
+            li    a1, -1
+            sd    a1, (a0)
         ",
         // 设置 stip
         "   li    a0, {mip_stip}
             csrrs zero, mip, a0
         ",
         // 恢复
-        "   ld    ra, 0*8(sp)
-            ld    a0, 1*8(sp)
-            ld    a1, 2*8(sp)
-            ld    a2, 3*8(sp)
-            addi  sp, sp,  4*8
+        "   ld    a0, 0*8(sp)
+            ld    a1, 1*8(sp)
+            addi  sp, sp,  2*8
         ",
         // 换栈：
         // sp      : S sp
@@ -77,54 +74,10 @@ unsafe extern "C" fn mtimer() {
         "   csrrw sp, mscratch, sp",
         // 返回
         "   mret",
-        mip_stip     = const 1 << 5,
-        clint_ptr    =   sym CLINT,
-        //                   Clint::write_mtimecmp_naked(&self, hart_idx, val)
-        set_mtimecmp =   sym Clint::write_mtimecmp_naked,
-        options(noreturn)
-    )
-}
-
-/// machine soft 中断代理
-///
-/// # Safety
-///
-/// 裸函数。
-#[naked]
-unsafe extern "C" fn msoft() {
-    asm!(
-        // 换栈：
-        // sp      : M sp
-        // mscratch: S sp
-        "   csrrw sp, mscratch, sp",
-        // 保护
-        "   addi sp, sp, -3*8
-            sd   ra, 0*8(sp)
-            sd   a0, 1*8(sp)
-            sd   a1, 2*8(sp)
-        ",
-        // 清除 msip 设置 ssip
-        "   la   a0, {clint_ptr}
-            ld   a0, (a0)
-            csrr a1, mhartid
-            call {clear_msip}
-            csrrsi zero, mip, 1 << 1
-        ",
-        // 恢复
-        "   ld   ra, 0*8(sp)
-            ld   a0, 1*8(sp)
-            ld   a1, 2*8(sp)
-            addi sp, sp,  3*8
-        ",
-        // 换栈：
-        // sp      : S sp
-        // mscratch: M sp
-        "   csrrw sp, mscratch, sp",
-        // 返回
-        "   mret",
-        clint_ptr  = sym CLINT,
-        //               Clint::clear_msip_naked(&self, hart_idx)
-        clear_msip = sym Clint::clear_msip_naked,
+        mip_stip        = const 1 << 5,
+        harts           =   sym HARTS,
+        hart_local_bits = const HartLocal::SIZE_BITS,
+        mtimecmp        = const core::mem::offset_of!(HartLocal, mtimecmp),
         options(noreturn)
     )
 }
@@ -4,10 +4,95 @@ use uart16550::Uart16550;
 
 pub(crate) static UART: Mutex<Uart16550Map> = Mutex::new(Uart16550Map(null()));
 
+/// 接收缓冲。
+///
+/// 串口接收中断和读取前都把串口中的数据移进这里，读取时从这里取出，不会阻塞。
+static RX: Mutex<RxBuffer> = Mutex::new(RxBuffer {
+    buf: [0; RX_LEN],
+    head: 0,
+    len: 0,
+});
+
+/// 接收缓冲的长度，缓冲满时丢弃新收到的数据。
+const RX_LEN: usize = 256;
+
 pub(crate) fn init(base: usize) {
     *UART.lock() = Uart16550Map(base as _);
 }
 
+/// 打开串口的接收中断。
+pub(crate) fn enable_rx_interrupt() {
+    let uart = UART.lock();
+    let ier = uart.get().ier();
+    ier.write(ier.read().enable_rda());
+}
+
+/// 把串口中的数据移进接收缓冲。
+pub(crate) fn receive() {
+    let uart = UART.lock();
+    let mut rx = RX.lock();
+    let mut c = 0u8;
+    while uart.get().read(core::slice::from_mut(&mut c)) == 1 {
+        rx.push(c);
+    }
+}
+
+/// 从接收缓冲读取到 `buf`，返回读取的字节数。
+pub(crate) fn read(buf: &mut [u8]) -> usize {
+    receive();
+    let mut rx = RX.lock();
+    let mut count = 0;
+    for c in buf {
+        match rx.pop() {
+            Some(b) => *c = b,
+            None => break,
+        }
+        count += 1;
+    }
+    count
+}
+
+/// 从接收缓冲读取一个字节。
+#[inline]
+pub(crate) fn getchar() -> Option<u8> {
+    let mut c = 0u8;
+    (read(core::slice::from_mut(&mut c)) == 1).then_some(c)
+}
+
+/// 是否收到了数据。
+#[inline]
+pub(crate) fn has_input() -> bool {
+    receive();
+    RX.lock().len != 0
+}
+
+struct RxBuffer {
+    buf: [u8; RX_LEN],
+    head: usize,
+    len: usize,
+}
+
+impl RxBuffer {
+    #[inline]
+    fn push(&mut self, c: u8) {
+        if self.len < RX_LEN {
+            self.buf[(self.head + self.len) % RX_LEN] = c;
+            self.len += 1;
+        }
+    }
+
+    #[inline]
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let c = self.buf[self.head];
+        self.head = (self.head + 1) % RX_LEN;
+        self.len -= 1;
+        Some(c)
+    }
+}
+
 pub struct Uart16550Map(*const Uart16550<u8>);
 
 unsafe impl Send for Uart16550Map {}
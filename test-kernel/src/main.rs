@@ -6,7 +6,11 @@
 #[macro_use]
 extern crate rcore_console;
 
-use core::{arch::asm, ptr::null};
+use core::{
+    arch::asm,
+    ptr::null,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use sbi_testing::sbi;
 use uart16550::Uart16550;
 
@@ -34,6 +38,15 @@ unsafe extern "C" fn _start(hartid: usize, device_tree_paddr: usize) -> ! {
     )
 }
 
+/// 热重启的次数。
+const NUM_WARM_REBOOTS: usize = 3;
+
+/// 已经完成的热重启次数。
+///
+/// 热重启不重新加载内核，`.data` 中的计数会保留下来，`.bss` 每次启动都会清零。
+#[link_section = ".data"]
+static REBOOTS: AtomicUsize = AtomicUsize::new(0);
+
 extern "C" fn rust_main(hartid: usize, dtb_pa: usize) -> ! {
     extern "C" {
         static mut sbss: u64;
@@ -75,11 +88,22 @@ extern "C" fn rust_main(hartid: usize, dtb_pa: usize) -> ! {
         hart_mask_base: 0,
         delay: frequency,
     };
-    if testing.test() {
-        sbi::system_reset(sbi::Shutdown, sbi::NoReason);
-    } else {
+    if !testing.test() {
         sbi::system_reset(sbi::Shutdown, sbi::SystemFailure);
     }
+    // 热重启后重新测试，确认固件可以反复重新初始化
+    let reboots = REBOOTS.fetch_add(1, Ordering::Relaxed);
+    if reboots < NUM_WARM_REBOOTS {
+        println!(
+            "[test-kernel] warm reboot {}/{NUM_WARM_REBOOTS}",
+            reboots + 1
+        );
+        let ret = sbi::system_reset(sbi::WarmReboot, sbi::NoReason);
+        println!("[test-kernel] warm reboot failed: {ret:?}");
+        sbi::system_reset(sbi::Shutdown, sbi::SystemFailure);
+    } else {
+        sbi::system_reset(sbi::Shutdown, sbi::NoReason);
+    }
     unreachable!()
 }
 
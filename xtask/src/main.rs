@@ -52,6 +52,12 @@ struct BuildArgs {
     /// Log level.
     #[clap(long)]
     log: Option<String>,
+    /// Stack size per hart in bytes.
+    #[clap(long)]
+    stack_size: Option<usize>,
+    /// Log level of the hart CSR report, `off` to disable.
+    #[clap(long)]
+    hart_report: Option<String>,
     /// Build in debug mode.
     #[clap(long)]
     debug: bool,
@@ -65,6 +71,12 @@ impl BuildArgs {
             .optional(&self.log, |cargo, log| {
                 cargo.env("LOG", log);
             })
+            .optional(&self.stack_size, |cargo, size| {
+                cargo.env("STACK_SIZE", size.to_string());
+            })
+            .optional(&self.hart_report, |cargo, level| {
+                cargo.env("HART_REPORT", level);
+            })
             .conditional(!self.debug, |cargo| {
                 cargo.release();
             })
@@ -129,6 +141,9 @@ struct QemuArgs {
     /// Port for gdb to connect. If set, qemu will block and wait gdb to connect.
     #[clap(long)]
     gdb: Option<u16>,
+    /// Interrupt controllers of virt machine, aplic or aplic-imsic. Use PLIC if not set.
+    #[clap(long)]
+    aia: Option<String>,
 }
 
 impl QemuArgs {
@@ -145,8 +160,12 @@ impl QemuArgs {
             "bench" | "bench-kernel" => self.build.make("bench-kernel", true),
             _ => panic!(),
         };
+        let machine = match self.aia.as_deref() {
+            Some(aia) => format!("virt,aia={aia}"),
+            None => "virt".into(),
+        };
         let status = Qemu::system("riscv64")
-            .args(["-machine", "virt"])
+            .args(["-machine", &machine])
             .arg("-nographic")
             .arg("-bios")
             .arg(sbi)